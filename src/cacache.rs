@@ -0,0 +1,302 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+use crate::{errors::CustomErrors, serde::CachedResponse};
+
+/// Accept headers npm sends for abbreviated and full package manifests
+const ACCEPT_ABBREVIATED: &str =
+    "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+const ACCEPT_FULL: &str = "application/json";
+
+/// Where downloaded packages are written
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum OutputFormat {
+    /// Loose tarballs, along with an index.json
+    Tarballs,
+    /// npm cache directory, for `npm install --offline --cache <output>`
+    Cacache,
+}
+
+/// npm cache (`<cache>/_cacache`) holding responses the way npm's HTTP client stores them:
+/// bodies under `content-v2` by digest, and request entries under `index-v5` by key
+#[derive(Debug, Clone)]
+pub(super) struct Cacache {
+    dir: PathBuf,
+}
+
+impl Cacache {
+    /// Cache of an npm cache directory (the `--cache` option of npm)
+    pub(super) fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.join("_cacache"),
+        }
+    }
+
+    /// Store the full manifest of a package, restricted to the bundled `versions`. npm asks for
+    /// abbreviated or full manifests depending on its version and options, full ones answer both
+    pub(super) fn put_packument(
+        &self,
+        packument: &CachedResponse,
+        versions: &[&str],
+    ) -> Result<(), CustomErrors> {
+        // validators of the registry's manifest don't apply to the trimmed one
+        let body = trim_packument(&packument.body, versions)?;
+
+        for accept in [ACCEPT_ABBREVIATED, ACCEPT_FULL] {
+            self.put(
+                &packument.url,
+                body.as_bytes(),
+                &[("accept", accept)],
+                &[("content-type", "application/json")],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Store a package tarball downloaded from `url`
+    pub(super) fn put_tarball(&self, url: &str, content: &[u8]) -> Result<PathBuf, CustomErrors> {
+        self.put(
+            url,
+            content,
+            &[],
+            &[("content-type", "application/octet-stream")],
+        )
+    }
+
+    /// Store the response body of a GET request to `url`, answered with `res_headers` to a
+    /// request with `req_headers`. npm looks responses up by URL, then matches request headers
+    /// (`accept` must be the one npm sends). Returns the path of the stored content
+    fn put(
+        &self,
+        url: &str,
+        content: &[u8],
+        req_headers: &[(&str, &str)],
+        res_headers: &[(&str, &str)],
+    ) -> Result<PathBuf, CustomErrors> {
+        // content is read with the strongest hash of the entry. The SHA-1 one lets npm match
+        // entries of legacy packages, only known by their shasum
+        let sha512 = Sha512::digest(content).to_vec();
+        let sha1 = Sha1::digest(content).to_vec();
+        let path = self.write_content(&sha512, content)?;
+
+        let headers = |headers: &[(&str, &str)]| {
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), Value::from(*value)))
+                .collect::<Map<_, _>>()
+        };
+        let time = Utc::now().timestamp_millis();
+        let key = format!("make-fetch-happen:request-cache:{url}");
+        let entry = json!({
+            "key": key,
+            "integrity": format!("sha512-{} sha1-{}", STANDARD.encode(sha512), STANDARD.encode(sha1)),
+            "time": time,
+            "size": content.len(),
+            "metadata": {
+                "time": time,
+                "url": url,
+                "reqHeaders": headers(req_headers),
+                "resHeaders": headers(res_headers),
+                "options": { "compress": true },
+            },
+        })
+        .to_string();
+
+        // buckets are append-only, the last entry of a key wins
+        let bucket = self
+            .dir
+            .join("index-v5")
+            .join(segments(&Sha256::digest(&key)));
+        let dir = bucket.parent().expect("buckets are in a subdirectory");
+        fs::create_dir_all(dir).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        let line = format!(
+            "\n{}\t{entry}",
+            base16ct::lower::encode_string(&Sha1::digest(&entry))
+        );
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&bucket)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| CustomErrors::Fs(format!("{}: {e}", bucket.display())))?;
+
+        Ok(path)
+    }
+
+    /// Write content under its SHA-512 digest, unless already there
+    fn write_content(&self, sha512: &[u8], content: &[u8]) -> Result<PathBuf, CustomErrors> {
+        let path = self
+            .dir
+            .join("content-v2")
+            .join("sha512")
+            .join(segments(sha512));
+        if fs::metadata(&path).is_ok_and(|m| m.len() == content.len() as u64) {
+            return Ok(path);
+        }
+
+        let dir = path.parent().expect("contents are in a subdirectory");
+        fs::create_dir_all(dir).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        fs::rename(&tmp, &path).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+
+        Ok(path)
+    }
+}
+
+/// Package manifest without the versions left out of the bundle, so npm only picks versions
+/// it has tarballs of. Dist-tags of removed versions are dropped, and "latest" points to the
+/// highest bundled version when it was removed
+fn trim_packument(body: &str, versions: &[&str]) -> Result<String, CustomErrors> {
+    let mut packument = serde_json::from_str::<Value>(body)
+        .map_err(|e| CustomErrors::BodyParse("JSON".to_string(), e.to_string()))?;
+    let Some(fields) = packument.as_object_mut() else {
+        return Err(CustomErrors::BodyParse(
+            "JSON".to_string(),
+            "package manifest isn't an object".to_string(),
+        ));
+    };
+
+    if let Some(all) = fields.get_mut("versions").and_then(Value::as_object_mut) {
+        all.retain(|v, _| versions.contains(&v.as_str()));
+    }
+
+    let tags = fields
+        .entry("dist-tags")
+        .or_insert_with(|| Value::Object(Map::new()));
+    if let Some(tags) = tags.as_object_mut() {
+        tags.retain(|_, v| v.as_str().is_some_and(|v| versions.contains(&v)));
+
+        let highest = versions
+            .iter()
+            .filter_map(|v| semver::Version::parse(v).ok())
+            .max();
+        if let (false, Some(highest)) = (tags.contains_key("latest"), highest) {
+            tags.insert("latest".to_string(), Value::from(highest.to_string()));
+        }
+    }
+
+    Ok(packument.to_string())
+}
+
+/// Relative path of a digest: "ab/cd/ef01..."
+fn segments(digest: &[u8]) -> PathBuf {
+    let hex = base16ct::lower::encode_string(digest);
+    [&hex[..2], &hex[2..4], &hex[4..]].iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    const PACKUMENT: &str = r#"{
+        "name": "b",
+        "dist-tags": {"latest": "1.1.0", "next": "2.0.0-rc.1", "legacy": "1.0.0"},
+        "versions": {
+            "1.0.0": {"name": "b", "version": "1.0.0"},
+            "1.1.0": {"name": "b", "version": "1.1.0"},
+            "2.0.0-rc.1": {"name": "b", "version": "2.0.0-rc.1"}
+        }
+    }"#;
+
+    #[test]
+    fn trims_versions_and_tags() {
+        let trimmed: Value =
+            serde_json::from_str(&trim_packument(PACKUMENT, &["1.0.0"]).unwrap()).unwrap();
+
+        assert_eq!(
+            trimmed["versions"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["1.0.0"]
+        );
+        assert_eq!(
+            trimmed["dist-tags"],
+            json!({"latest": "1.0.0", "legacy": "1.0.0"})
+        );
+    }
+
+    #[test]
+    fn keeps_latest_when_bundled() {
+        let trimmed: Value =
+            serde_json::from_str(&trim_packument(PACKUMENT, &["1.0.0", "1.1.0"]).unwrap()).unwrap();
+
+        assert_eq!(trimmed["dist-tags"]["latest"], "1.1.0");
+        assert_eq!(trimmed["versions"].as_object().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stores_trimmed_packument() {
+        let dir = env::temp_dir().join(format!("npm-offline-cacache-{}", process::id()));
+        let cacache = Cacache::new(&dir);
+        let packument = CachedResponse {
+            url: "http://registry.test/b".to_string(),
+            etag: Some("\"etag\"".to_string()),
+            last_modified: None,
+            body: PACKUMENT.to_string(),
+        };
+        cacache.put_packument(&packument, &["1.0.0"]).unwrap();
+
+        let key = "make-fetch-happen:request-cache:http://registry.test/b";
+        let bucket = cacache
+            .dir
+            .join("index-v5")
+            .join(segments(&Sha256::digest(key)));
+        let index = fs::read_to_string(bucket).unwrap();
+        let entries = index
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| {
+                let (hash, entry) = l.split_once('\t').unwrap();
+                assert_eq!(hash, base16ct::lower::encode_string(&Sha1::digest(entry)));
+                serde_json::from_str::<Value>(entry).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+
+        for entry in entries {
+            assert_eq!(entry["key"], key);
+            assert!(entry["metadata"]["resHeaders"].get("etag").is_none());
+
+            let integrity = entry["integrity"].as_str().unwrap();
+            let digest = STANDARD
+                .decode(
+                    integrity
+                        .split_whitespace()
+                        .next()
+                        .unwrap()
+                        .strip_prefix("sha512-")
+                        .unwrap(),
+                )
+                .unwrap();
+            let body = fs::read(
+                cacache
+                    .dir
+                    .join("content-v2/sha512")
+                    .join(segments(&digest)),
+            )
+            .unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["dist-tags"]["latest"], "1.0.0");
+            assert!(body["versions"].get("1.1.0").is_none());
+        }
+
+        assert!(!cacache.dir.join("content-v2/sha1").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
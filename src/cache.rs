@@ -0,0 +1,65 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{errors::CustomErrors, serde::CachedResponse};
+
+/// On-disk cache of registry responses, keyed by request
+#[derive(Debug, Clone)]
+pub(super) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Open a cache directory, or the default one of the user
+    pub(super) fn new(dir: Option<&Path>) -> Self {
+        Self {
+            dir: cache_dir(dir).join("packuments"),
+        }
+    }
+
+    /// Cached response of a request. Unreadable entries are treated as missing
+    pub(super) fn get(&self, key: &str) -> Option<CachedResponse> {
+        let content = fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    /// Store the response of a request, replacing the previous one atomically
+    pub(super) fn put(&self, key: &str, response: &CachedResponse) -> Result<(), CustomErrors> {
+        fs::create_dir_all(&self.dir).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        let content = serde_json::to_vec(response).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        fs::write(&tmp, content).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        fs::rename(&tmp, &path).map_err(|e| CustomErrors::Fs(e.to_string()))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let hash = base16ct::lower::encode_string(&Sha1::digest(key.as_bytes()));
+        self.dir.join(format!("{hash}.json"))
+    }
+}
+
+/// Cache directory given by the user, or the default one
+pub(super) fn cache_dir(dir: Option<&Path>) -> PathBuf {
+    match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => default_dir(),
+    }
+}
+
+/// User cache directory: $XDG_CACHE_HOME, ~/.cache or %LOCALAPPDATA%. Without any of them,
+/// the cache lives in the temporary directory
+fn default_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_else(env::temp_dir);
+
+    base.join("npm-offline")
+}
@@ -1,41 +1,308 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    fs,
-    path::PathBuf,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    path::{Path, PathBuf},
 };
 
-use semver::VersionReq;
-
 use crate::{
     btree_insert_cond,
+    cacache::{Cacache, OutputFormat},
     errors::CustomErrors,
-    registry::{manuel_extend, Registry},
-    serde::PackageJson,
-    utils::split_package_string,
+    npmrc::Npmrc,
+    registry::{Registries, RegistryOptions},
+    resolver::{
+        DependencyKind, Inputs, Packages, Requirement, Resolution, ResolveOptions, Resolver,
+    },
+    serde::{Dist, Index, IndexEntry},
+    store::LinkMethod,
+    utils::{missing_bundled, split_package_string},
     version::parse,
+    workspaces::{self, is_local, read_package_json, Locals},
 };
 
-/// List of versions of a package
-type PackageVersions = HashSet<Option<Vec<VersionReq>>>;
-type PackageList = BTreeMap<String, PackageVersions>;
+/// Options of the download command
+#[derive(clap::Args, Debug, Clone)]
+pub(super) struct DownloadOptions {
+    /// Output directory for all tarballs
+    #[arg(short = 'o', long, default_value_t = String::from("./packages"))]
+    output: String,
+
+    /// Compress tarballs into a single one. Output path will be "output" the flag with ".tar.gz" extension
+    #[arg(short, long)]
+    compress: bool,
+
+    /// Check that bundleDependencies are shipped inside downloaded tarballs
+    #[arg(long)]
+    verify_bundled: bool,
+
+    /// How tarballs from the shared store (in the cache directory) are placed into the output
+    #[arg(long, value_enum, default_value_t = LinkMethod::Auto)]
+    link: LinkMethod,
+
+    /// Layout of the output. "cacache" fills an npm cache, with package manifests, for
+    /// `npm install --offline --cache <output>` to work without any registry
+    #[arg(long, value_enum, default_value_t = OutputFormat::Tarballs)]
+    format: OutputFormat,
+}
 
 pub(super) fn download(
     args: Vec<String>,
-    output: String,
-    dev: bool,
-    peer: bool,
-    optional: bool,
-    registry: Option<String>,
-    _compress: bool,
-    dispatch: bool,
+    download: DownloadOptions,
+    options: ResolveOptions,
+    registry: RegistryOptions,
+) -> Result<(), CustomErrors> {
+    let inputs = collect_inputs(args, &options)?;
+    let registries = Registries::new(&Npmrc::load(), &registry)?;
+
+    let resolution = Resolver::new(&registries, &options).resolve(inputs)?;
+    report(&resolution);
+    let tbd = resolution.packages;
+
+    println!("Downloading {} packages...", tbd.len());
+
+    let output = Path::new(&download.output);
+    let cacache = Cacache::new(output);
+
+    let mut index: Index = BTreeMap::new();
+    tbd.iter().for_each(|(package, versions)| {
+        let source = resolution.sources.get(package);
+
+        // npm reads package manifests before tarballs, and picks versions among theirs
+        if download.format == OutputFormat::Cacache {
+            let bundled = versions.keys().map(String::as_str).collect::<Vec<_>>();
+            let stored = registries
+                .packument_response(package, source.map(String::as_str))
+                .and_then(|packument| cacache.put_packument(&packument, &bundled));
+            if let Err(e) = stored {
+                println!("{package}: Failed to store package manifest => {e}");
+            }
+        }
+
+        versions.iter().for_each(|(tag, manifest)| {
+            let x = match download.format {
+                OutputFormat::Tarballs => registries.download_tarball(
+                    package,
+                    source.map(String::as_str),
+                    &manifest.dist,
+                    output,
+                    download.link,
+                ),
+                OutputFormat::Cacache => {
+                    cache_tarball(&registries, &cacache, package, source, &manifest.dist)
+                }
+            };
+
+            if let Ok((path, tarball)) = &x {
+                index.entry(package.clone()).or_default().insert(
+                    tag.clone(),
+                    IndexEntry {
+                        registry: source.cloned(),
+                        tarball: tarball.clone(),
+                        file: match download.format {
+                            OutputFormat::Tarballs => file_name(path),
+                            OutputFormat::Cacache => relative(path, output),
+                        },
+                    },
+                );
+            }
+
+            match x.map(|(path, _)| path) {
+                Ok(path) if download.verify_bundled => {
+                    match missing_bundled(Path::new(&path), manifest.bundled()) {
+                        Ok(missing) if !missing.is_empty() => println!(
+                            "{package}@{tag}: bundled dependencies missing from tarball => {}",
+                            missing.join(", ")
+                        ),
+                        Ok(_) => {}
+                        Err(e) => println!("{package}@{tag}: Failed to inspect tarball => {e}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => println!("{package}@{tag}: Failed to download => {e}"),
+            }
+        });
+    });
+
+    write_index(output, &index)?;
+
+    println!("Packages downloaded!");
+    report_deprecated(&tbd);
+
+    Ok(())
+}
+
+/// Add a package tarball to an npm cache, under the URL npm requests it from
+/// (the `tarball` of its manifest). Returns the path of the tarball along with the URL it
+/// was downloaded from
+fn cache_tarball(
+    registries: &Registries,
+    cacache: &Cacache,
+    package: &str,
+    source: Option<&String>,
+    dist: &Dist,
+) -> Result<(String, String), CustomErrors> {
+    let (stored, url) = registries.stored_tarball(package, source.map(String::as_str), dist)?;
+    let content = fs::read(&stored).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+    let path = cacache.put_tarball(&dist.tarball, &content)?;
+
+    Ok((path.to_string_lossy().to_string(), url))
+}
+
+pub(super) fn resolve(
+    args: Vec<String>,
+    options: ResolveOptions,
+    registry: RegistryOptions,
+) -> Result<(), CustomErrors> {
+    let inputs = collect_inputs(args, &options)?;
+    let registries = Registries::new(&Npmrc::load(), &registry)?;
+
+    let resolution = Resolver::new(&registries, &options).resolve(inputs)?;
+    report(&resolution);
+
+    let mut resolved = resolution
+        .packages
+        .iter()
+        .flat_map(|(name, versions)| versions.keys().map(move |v| format!("{name}@{v}")))
+        .collect::<Vec<_>>();
+    resolved.sort();
+
+    println!("Resolved {} packages:", resolved.len());
+    resolved.iter().for_each(|p| println!("  {p}"));
+    report_deprecated(&resolution.packages);
+
+    Ok(())
+}
+
+/// Print every dependency path leading from the inputs to a package
+pub(super) fn why(
+    package: String,
+    args: Vec<String>,
+    options: ResolveOptions,
+    registry: RegistryOptions,
 ) -> Result<(), CustomErrors> {
-    let mut pkgs: PackageList = BTreeMap::new();
+    let (name, range) = split_package_string(&package)?;
+    let req = if range == "latest" {
+        None
+    } else {
+        Some(parse(&range)?)
+    };
+
+    let inputs = collect_inputs(args, &options)?;
+    let registries = Registries::new(&Npmrc::load(), &registry)?;
+
+    let resolution = Resolver::new(&registries, &options).resolve(inputs)?;
+    report(&resolution);
+
+    let mut versions = resolution
+        .packages
+        .get(&name)
+        .map(|versions| versions.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|v| semver::Version::parse(&v).ok())
+        .filter(|v| {
+            req.as_ref()
+                .is_none_or(|req| req.iter().any(|r| r.matches(v)))
+        })
+        .collect::<Vec<_>>();
+    versions.sort();
+
+    if versions.is_empty() {
+        println!("{package}: not found in resolved dependencies");
+        return Ok(());
+    }
+
+    for version in versions {
+        let paths = resolution.paths_to(&(name.clone(), version.to_string()));
+
+        println!("\n{name}@{version}: {} path(s)", paths.len());
+        for path in paths {
+            let hops = path
+                .iter()
+                .map(|e| format!("{}@{} ({} \"{}\")", e.to.0, e.to.1, e.kind, e.range))
+                .collect::<Vec<_>>();
+
+            println!("  {}", hops.join(" > "));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the index of a bundle, recording where each downloaded package came from
+fn write_index(output: &Path, index: &Index) -> Result<(), CustomErrors> {
+    fs::create_dir_all(output).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+    let f = File::create(output.join("index.json")).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+
+    serde_json::to_writer_pretty(f, index).map_err(|e| CustomErrors::Fs(e.to_string()))
+}
+
+/// Path of a file of the output directory, relative to it
+fn relative(path: &str, output: &Path) -> String {
+    Path::new(path)
+        .strip_prefix(output)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or(path.to_string())
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Print problems encountered during a resolution
+fn report(resolution: &Resolution) {
+    if resolution.warnings.is_empty() {
+        return;
+    }
+
+    println!(
+        "{} warning(s) during resolution:",
+        resolution.warnings.len()
+    );
+    resolution.warnings.iter().for_each(|w| println!("  {w}"));
+}
+
+/// Print deprecated packages part of a resolution, with their deprecation message
+fn report_deprecated(packages: &Packages) {
+    let mut deprecated = packages
+        .iter()
+        .flat_map(|(name, versions)| {
+            versions.iter().filter_map(move |(version, manifest)| {
+                manifest
+                    .deprecated
+                    .as_ref()
+                    .map(|msg| (format!("{name}@{version}"), msg))
+            })
+        })
+        .collect::<Vec<_>>();
+    if deprecated.is_empty() {
+        return;
+    }
+    deprecated.sort();
+
+    println!("{} deprecated package(s):", deprecated.len());
+    deprecated
+        .iter()
+        .for_each(|(package, msg)| println!("  {package}: {msg}"));
+}
+
+/// Gather requested packages from a list of package strings (express@4.18.2) or "package.json" paths.
+/// Dependencies of workspaces declared by a package.json are gathered as well
+fn collect_inputs(args: Vec<String>, options: &ResolveOptions) -> Result<Inputs, CustomErrors> {
+    let mut inputs = Inputs::default();
 
     for arg in args {
         let mut path = PathBuf::from(arg.clone());
         if !path.exists() {
-            let (name, version) = split_package_string(arg)?;
-            pkgs.entry(name).or_default().insert(version);
+            let (name, range) = split_package_string(&arg)?;
+            inputs
+                .packages
+                .entry(name)
+                .or_default()
+                .insert(Requirement::parse(&range, DependencyKind::Prod)?);
 
             continue;
         }
@@ -50,58 +317,67 @@ pub(super) fn download(
             }
         }
 
-        let pkg_json: PackageJson = serde_json::from_str(
-            &fs::read_to_string(path).map_err(|e| CustomErrors::Fs(e.to_string()))?,
-        )
-        .map_err(|e| CustomErrors::Fs(e.to_string()))?;
-
-        btree_insert_cond!(
-            (true, pkgs, pkg_json.dependencies),
-            (dev, pkgs, pkg_json.dev_dependencies),
-            (peer, pkgs, pkg_json.peer_dependencies),
-            (optional, pkgs, pkg_json.optional_dependencies)
-        );
-    }
-
-    let registry = Registry::new(registry)?;
+        let pkg_json = read_package_json(&path)?;
+        let root = path.parent().unwrap_or(Path::new("."));
+        let workspaces = workspaces::expand(root, &pkg_json)?;
+        let locals: Locals = workspaces
+            .iter()
+            .map(|w| (w.name.clone(), w.version.clone()))
+            .collect();
 
-    let mut tbd = HashMap::new();
+        let root_deps = pkg_json
+            .optional_dependencies
+            .iter()
+            .chain(&pkg_json.peer_dependencies)
+            .chain(&pkg_json.dev_dependencies)
+            .chain(&pkg_json.dependencies)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        inputs.overrides.add_npm(&pkg_json.overrides, &root_deps)?;
+        inputs.overrides.add_yarn(&pkg_json.resolutions)?;
 
-    for (name, version) in pkgs {
-        for v in version {
-            println!("{name}: Resolving dependencies...");
+        for pkg_json in std::iter::once(&pkg_json).chain(&workspaces) {
+            // workspace packages are local, only their own dependencies are fetched
+            let remote = |deps: &HashMap<String, String>| {
+                deps.iter()
+                    .filter(|(name, range)| !is_local(&locals, name, range))
+                    .map(|(name, range)| (name.clone(), range.clone()))
+                    .collect::<Vec<_>>()
+            };
 
-            manuel_extend(
-                registry.fetch_dependencies(name.clone(), v, dev, peer, optional, dispatch)?,
-                &mut tbd,
+            btree_insert_cond!(
+                (
+                    true,
+                    inputs.packages,
+                    remote(&pkg_json.dependencies),
+                    DependencyKind::Prod
+                ),
+                (
+                    options.includes(DependencyKind::Dev, 0),
+                    inputs.packages,
+                    remote(&pkg_json.dev_dependencies),
+                    DependencyKind::Dev
+                ),
+                (
+                    options.includes(DependencyKind::Peer, 0),
+                    inputs.packages,
+                    remote(&pkg_json.peer_dependencies),
+                    DependencyKind::Peer
+                ),
+                (
+                    options.includes(DependencyKind::Optional, 0) || !options.targets.is_empty(),
+                    inputs.packages,
+                    remote(&pkg_json.optional_dependencies),
+                    DependencyKind::Optional
+                )
             );
-
-            println!("{name}: Resolved");
         }
     }
 
-    println!("Downloading {} packages...", tbd.len());
-
-    tbd.iter().for_each(|(package, versions)| {
-        versions.iter().for_each(|(tag, manifest)| {
-            let x = registry.download_tarball(
-                manifest.dist.shasum.to_owned(),
-                manifest.dist.tarball.to_owned(),
-                &output,
-            );
-
-            if let Err(e) = x {
-                println!("{package}@{tag}: Failed to download => {e}");
-            }
-        });
-    });
-
-    println!("Packages downloaded!");
-
-    Ok(())
+    Ok(inputs)
 }
 
 #[allow(dead_code)]
-pub(super) fn publish(_pkgs: Vec<String>, _registry: Option<String>) -> Result<(), CustomErrors> {
+pub(super) fn publish(_pkgs: Vec<String>, _registry: RegistryOptions) -> Result<(), CustomErrors> {
     todo!()
 }
@@ -15,6 +15,15 @@ pub(crate) enum CustomErrors {
     #[error("failed to parse HTTP header to create client: {}", .0)]
     HttpHeaderParse(String),
 
+    #[error("failed to read npm configuration: {}", .0)]
+    Npmrc(String),
+
+    #[error("package not found: {}", .0)]
+    PackageNotFound(String),
+
+    #[error("request failed: {}", .0)]
+    Request(String),
+
     #[error("failed to fetch package manifest: {}", .0)]
     PackageManifestFetch(String),
 
@@ -27,6 +36,9 @@ pub(crate) enum CustomErrors {
     #[error("Version error: {}", .0)]
     Version(String),
 
+    #[error("failed to resolve {} dependencies:\n  {}", .0.len(), .0.join("\n  "))]
+    Resolution(Vec<String>),
+
     #[error("Error: {}", .0)]
     Global(String),
 }
@@ -1,21 +1,28 @@
 use clap::{Parser, Subcommand};
-use commands::{download, publish};
+use commands::{download, publish, resolve, why, DownloadOptions};
+use registry::RegistryOptions;
+use resolver::ResolveOptions;
 
+mod cacache;
+mod cache;
 mod commands;
 mod errors;
 mod macros;
+mod npmrc;
 mod registry;
+mod resolver;
 mod serde;
-mod version;
+mod store;
 mod utils;
+mod version;
+mod workspaces;
 
 /// Download NodeJS dependencies from an npm registry for offline use
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Remote registry to use
-    #[arg(short, long)]
-    registry: Option<String>,
+    #[command(flatten)]
+    registry: RegistryOptions,
 
     #[command(subcommand)]
     subcommands: Subcommands,
@@ -30,28 +37,11 @@ enum Subcommands {
         #[arg(required = true)]
         packages: Vec<String>,
 
-        /// Output directory for all tarballs
-        #[arg(short = 'o', long, default_value_t = String::from("./packages"))]
-        output: String,
-
-        /// Include devDependencies
-        #[arg(short = 'd', long)]
-        dev_dependencies: bool,
-
-        /// Include peerDependencies
-        #[arg(long)]
-        peer_dependencies: bool,
-
-        /// Include optionalDependencies
-        #[arg(long)]
-        optional_dependencies: bool,
+        #[command(flatten)]
+        download: DownloadOptions,
 
-        #[arg(long)]
-        dispatch_sub_dependencies: bool,
-
-        /// Compress tarballs into a single one. Output path will be "output" the flag with ".tar.gz" extension
-        #[arg(short, long)]
-        compress: bool,
+        #[command(flatten)]
+        options: ResolveOptions,
     },
 
     /// Publish tarballs dependencies to an npm registry
@@ -60,8 +50,29 @@ enum Subcommands {
         packages: Vec<String>,
     },
 
+    /// Resolve and list dependencies of given packages without downloading them
     Resolve {
+        /// List of packages with their version (express@4.18.2) or list of "package.json" files.
+        /// Space separated
+        #[arg(required = true)]
+        packages: Vec<String>,
+
+        #[command(flatten)]
+        options: ResolveOptions,
+    },
+
+    /// Explain why a package is part of the resolved dependencies
+    Why {
+        /// Package to explain, with an optional version range (debug@^2)
+        package: String,
+
+        /// List of packages with their version (express@4.18.2) or list of "package.json" files.
+        /// Space separated
+        #[arg(required = true)]
         packages: Vec<String>,
+
+        #[command(flatten)]
+        options: ResolveOptions,
     },
 }
 
@@ -73,24 +84,16 @@ fn main() {
     let res = match args.subcommands {
         Subcommands::Download {
             packages,
-            output,
-            dev_dependencies,
-            peer_dependencies,
-            optional_dependencies,
-            compress,
-            dispatch_sub_dependencies,
-        } => download(
-            packages,
-            output,
-            dev_dependencies,
-            peer_dependencies,
-            optional_dependencies,
-            remote_registry,
-            compress,
-            dispatch_sub_dependencies,
-        ),
+            download: download_options,
+            options,
+        } => download(packages, download_options, options, remote_registry),
         Subcommands::Publish { packages } => publish(packages, remote_registry),
-        Subcommands::Resolve { packages: _ } => todo!(),
+        Subcommands::Resolve { packages, options } => resolve(packages, options, remote_registry),
+        Subcommands::Why {
+            package,
+            packages,
+            options,
+        } => why(package, packages, options, remote_registry),
     };
 
     if let Err(e) = res {
@@ -0,0 +1,411 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use url::Url;
+
+use crate::errors::CustomErrors;
+
+const ENV_PREFIX: &str = "npm_config_";
+
+/// npm configuration, merged from `NPM_CONFIG_*` environment variables and the project,
+/// user and global `.npmrc` files, in that order of precedence
+#[derive(Debug, Clone, Default)]
+pub(super) struct Npmrc {
+    values: HashMap<String, String>,
+}
+
+impl Npmrc {
+    /// Load configuration the way npm does. Missing files are ignored
+    pub(super) fn load() -> Self {
+        let mut config = Self::default();
+
+        for (key, value) in env::vars() {
+            let Some(name) = key
+                .get(..ENV_PREFIX.len())
+                .filter(|p| p.eq_ignore_ascii_case(ENV_PREFIX))
+                .and_then(|_| key.get(ENV_PREFIX.len()..))
+                .filter(|name| !name.is_empty())
+            else {
+                continue;
+            };
+
+            config.values.entry(env_key(name)).or_insert(value);
+        }
+
+        if let Some(project) = project_root() {
+            config.merge_file(&project.join(".npmrc"));
+        }
+
+        let user = match config.get("userconfig") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => home_dir().map(|home| home.join(".npmrc")),
+        };
+        if let Some(user) = user {
+            config.merge_file(&user);
+        }
+
+        let global = match (config.get("globalconfig"), config.get("prefix")) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Some(prefix)) => Path::new(prefix).join("etc").join("npmrc"),
+            (None, None) => PathBuf::from("/usr/local/etc/npmrc"),
+        };
+        config.merge_file(&global);
+
+        config
+    }
+
+    /// Value of a configuration key
+    pub(super) fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Registries configured for scopes, as `(scope, url)` tuples (`@company:registry=...`)
+    pub(super) fn scoped_registries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().filter_map(|(key, url)| {
+            key.strip_suffix(":registry")
+                .filter(|scope| scope.starts_with('@'))
+                .map(|scope| (scope, url.as_str()))
+        })
+    }
+
+    /// Authorization header values configured for registries, keyed by URL prefix without
+    /// scheme (`//registry.company.com/npm/`), longest first. Unscoped legacy credentials
+    /// (`_authToken=...`) apply to the `default` registry prefix
+    pub(super) fn credentials(&self, default: &str) -> Result<Vec<(String, String)>, CustomErrors> {
+        let prefixes = self
+            .values
+            .keys()
+            .filter(|key| key.starts_with("//"))
+            .filter_map(|key| key.rsplit_once(':').map(|(prefix, _)| prefix))
+            .collect::<BTreeSet<_>>();
+
+        let mut credentials = vec![];
+        for prefix in prefixes {
+            if let Some(auth) = self.authorization(&format!("{prefix}:"))? {
+                credentials.push((with_slash(prefix), auth));
+            }
+        }
+        if !credentials.iter().any(|(p, _)| *p == with_slash(default)) {
+            if let Some(auth) = self.authorization("")? {
+                credentials.push((with_slash(default), auth));
+            }
+        }
+        credentials.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+
+        Ok(credentials)
+    }
+
+    /// Authorization header value from `_authToken`, `_auth` or `username`/`_password` keys
+    fn authorization(&self, key_prefix: &str) -> Result<Option<String>, CustomErrors> {
+        let get = |key: &str| self.get(&format!("{key_prefix}{key}"));
+
+        if let Some(token) = get("_authToken") {
+            return Ok(Some(format!("Bearer {token}")));
+        }
+        if let Some(auth) = get("_auth") {
+            return Ok(Some(format!("Basic {auth}")));
+        }
+
+        let (Some(username), Some(password)) = (get("username"), get("_password")) else {
+            return Ok(None);
+        };
+        let password = STANDARD.decode(password).map_err(|e| {
+            CustomErrors::Npmrc(format!("{key_prefix}_password isn't base64 encoded: {e}"))
+        })?;
+        let mut auth = format!("{username}:").into_bytes();
+        auth.extend(password);
+
+        Ok(Some(format!("Basic {}", STANDARD.encode(auth))))
+    }
+
+    /// Value of a numeric configuration key
+    pub(super) fn number(&self, key: &str) -> Result<Option<u64>, CustomErrors> {
+        self.get(key)
+            .map(|v| {
+                v.parse()
+                    .map_err(|e| CustomErrors::Npmrc(format!("invalid {key} \"{v}\": {e}")))
+            })
+            .transpose()
+    }
+
+    /// Value of a boolean configuration key. Anything but "false" is true, like npm
+    pub(super) fn flag(&self, key: &str) -> Option<bool> {
+        self.get(key).map(|v| v != "false")
+    }
+
+    /// Add keys from an ini file, without overriding keys already set
+    fn merge_file(&mut self, path: &Path) {
+        let Ok(content) = fs::read_to_string(path) else {
+            return;
+        };
+
+        for (key, value) in parse(&content) {
+            self.values.entry(expand(&key)).or_insert(expand(&value));
+        }
+    }
+}
+
+/// Parse `key = value` lines of an ini file. Sections and `key[]` arrays aren't used by the
+/// keys read here, they are skipped
+fn parse(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with(';') && !l.starts_with('#'))
+        .filter(|l| !l.starts_with('['))
+        .filter_map(|l| {
+            let (key, value) = l.split_once('=').unwrap_or((l, "true"));
+            let key = unquote(key.trim());
+            if key.ends_with("[]") {
+                return None;
+            }
+
+            Some((key, unquote(value.trim())))
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"'))
+            || (value.starts_with('\'') && value.ends_with('\'')));
+
+    if quoted {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Replace `${VAR}` references with environment variables. `${VAR?}` expands to an empty
+/// string when the variable is unset, while an unset `${VAR}` is kept as is, like npm does
+fn expand(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        // "\${VAR}" is kept as is
+        if rest[..start].ends_with('\\') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + end];
+        expanded.push_str(&rest[..start]);
+
+        match (name.strip_suffix('?'), env::var(name.trim_end_matches('?'))) {
+            (_, Ok(var)) => expanded.push_str(&var),
+            (Some(_), Err(_)) => {}
+            (None, Err(_)) => expanded.push_str(&rest[start..start + end + 1]),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    expanded
+}
+
+/// Configuration key of an `NPM_CONFIG_*` variable: lowercased, with "_" turned into "-"
+/// except for a leading one (NPM_CONFIG_STRICT_SSL => strict-ssl)
+fn env_key(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.strip_prefix('_') {
+        Some(rest) => format!("_{}", rest.replace('_', "-")),
+        None => name.replace('_', "-"),
+    }
+}
+
+/// Closest directory from the current one holding a package.json or node_modules,
+/// or the current directory
+fn project_root() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    let root = cwd
+        .ancestors()
+        .find(|dir| dir.join("package.json").exists() || dir.join("node_modules").exists())
+        .unwrap_or(&cwd);
+
+    Some(root.to_path_buf())
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// URL without scheme, query and fragment (`//registry.company.com/npm/pkg`), as used by
+/// npm to key credentials
+pub(super) fn nerf_dart(url: &Url) -> String {
+    let port = url.port().map(|p| format!(":{p}")).unwrap_or_default();
+    format!(
+        "//{}{port}{}",
+        url.host_str().unwrap_or_default(),
+        url.path()
+    )
+}
+
+fn with_slash(prefix: &str) -> String {
+    match prefix.ends_with('/') {
+        true => prefix.to_string(),
+        false => format!("{prefix}/"),
+    }
+}
+
+#[cfg(test)]
+impl Npmrc {
+    /// Configuration of a single .npmrc file
+    pub(super) fn from_ini(content: &str) -> Self {
+        let mut config = Self::default();
+        for (key, value) in parse(content) {
+            config.values.entry(key).or_insert(value);
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ini_lines() {
+        let parsed = parse(
+            "; comment\n# comment\n[section]\nregistry = \"https://r.test/\"\nflag\nca[] = x\n  key='a=b'  \n",
+        );
+
+        assert_eq!(
+            parsed,
+            [
+                ("registry".to_string(), "https://r.test/".to_string()),
+                ("flag".to_string(), "true".to_string()),
+                ("key".to_string(), "a=b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        env::set_var("NPM_OFFLINE_TEST_TOKEN", "secret");
+        env::remove_var("NPM_OFFLINE_TEST_UNSET");
+
+        assert_eq!(expand("a-${NPM_OFFLINE_TEST_TOKEN}-b"), "a-secret-b");
+        assert_eq!(expand("${NPM_OFFLINE_TEST_UNSET?}x"), "x");
+        assert_eq!(
+            expand("\\${NPM_OFFLINE_TEST_TOKEN}"),
+            "${NPM_OFFLINE_TEST_TOKEN}"
+        );
+        assert_eq!(expand("${unterminated"), "${unterminated");
+        assert_eq!(
+            expand("${NPM_OFFLINE_TEST_UNSET}"),
+            "${NPM_OFFLINE_TEST_UNSET}"
+        );
+    }
+
+    #[test]
+    fn env_keys() {
+        assert_eq!(env_key("STRICT_SSL"), "strict-ssl");
+        assert_eq!(env_key("registry"), "registry");
+        assert_eq!(env_key("_AUTH_TOKEN"), "_auth-token");
+    }
+
+    #[test]
+    fn first_value_wins() {
+        let config = Npmrc::from_ini("registry=https://a.test/\nregistry=https://b.test/\n");
+
+        assert_eq!(config.get("registry"), Some("https://a.test/"));
+    }
+
+    #[test]
+    fn typed_values() {
+        let config = Npmrc::from_ini(
+            "strict-ssl=false\nalways-auth=true\nfetch-retries=3\nfetch-timeout=soon\n",
+        );
+
+        assert_eq!(config.flag("strict-ssl"), Some(false));
+        assert_eq!(config.flag("always-auth"), Some(true));
+        assert_eq!(config.flag("missing"), None);
+        assert_eq!(config.number("fetch-retries").unwrap(), Some(3));
+        assert!(config.number("fetch-timeout").is_err());
+    }
+
+    #[test]
+    fn scoped_registries() {
+        let config = Npmrc::from_ini(
+            "@corp:registry=https://npm.corp.test/\nregistry=https://r.test/\n//r.test/:registry=x\n",
+        );
+
+        assert_eq!(
+            config.scoped_registries().collect::<Vec<_>>(),
+            [("@corp", "https://npm.corp.test/")]
+        );
+    }
+
+    #[test]
+    fn credentials_by_prefix() {
+        let config = Npmrc::from_ini(concat!(
+            "//npm.corp.test/:_authToken=corp\n",
+            "//npm.corp.test/private/:_auth=dXNlcjpwdw==\n",
+            "//basic.test/:username=user\n",
+            "//basic.test/:_password=cHc=\n",
+            "//incomplete.test/:username=user\n",
+        ));
+
+        assert_eq!(
+            config.credentials("//r.test/").unwrap(),
+            [
+                (
+                    "//npm.corp.test/private/".to_string(),
+                    "Basic dXNlcjpwdw==".to_string()
+                ),
+                ("//npm.corp.test/".to_string(), "Bearer corp".to_string()),
+                (
+                    "//basic.test/".to_string(),
+                    "Basic dXNlcjpwdw==".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_credentials_apply_to_default_registry() {
+        let config = Npmrc::from_ini("_authToken=legacy\n//other.test/:_authToken=other\n");
+        assert_eq!(
+            config.credentials("//r.test").unwrap(),
+            [
+                ("//other.test/".to_string(), "Bearer other".to_string()),
+                ("//r.test/".to_string(), "Bearer legacy".to_string()),
+            ]
+        );
+
+        // scoped credentials of the default registry take precedence
+        let config = Npmrc::from_ini("_authToken=legacy\n//r.test/:_authToken=scoped\n");
+        assert_eq!(
+            config.credentials("//r.test/").unwrap(),
+            [("//r.test/".to_string(), "Bearer scoped".to_string())]
+        );
+    }
+
+    #[test]
+    fn invalid_password() {
+        let config = Npmrc::from_ini("//r.test/:username=user\n//r.test/:_password=not base64!\n");
+        assert!(config.credentials("//r.test/").is_err());
+    }
+
+    #[test]
+    fn nerf_darts() {
+        let url = Url::parse("https://user:pw@npm.corp.test:8443/npm/?q=1#f").unwrap();
+        assert_eq!(nerf_dart(&url), "//npm.corp.test:8443/npm/");
+    }
+}
@@ -1,65 +1,609 @@
 use std::{
-    collections::HashMap,
-    fs::{create_dir, File},
-    io::{self, Write},
-    path::Path,
+    collections::{hash_map::RandomState, HashMap},
+    env, fs,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 
-use reqwest::header::{HeaderValue, ACCEPT, USER_AGENT};
-use semver::VersionReq;
+use chrono::{DateTime, Utc};
+
+use reqwest::{
+    blocking::RequestBuilder,
+    header::{
+        HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RETRY_AFTER, USER_AGENT,
+    },
+    Identity, NoProxy, Proxy, StatusCode,
+};
 use serde_json::Value;
-use sha1::{Digest, Sha1};
 use url::Url;
 
 use crate::{
+    cache::Cache,
     errors::CustomErrors,
-    hashmap, hashmap_ext_cond, headers,
-    serde::{PackageRsp, Version},
-    utils::find_version,
-    version::parse,
+    headers,
+    npmrc::{nerf_dart, Npmrc},
+    serde::{CachedResponse, Dist, PackageRsp},
+    store::{self, Integrity, LinkMethod, Store},
 };
 
 const REGISTRY_URL: &str = "https://registry.npmjs.org";
+const USER_AGENT_DEFAULT: &str = "npm-offline@v0.1.0";
+
+/// Options of the registries packages are fetched from
+#[derive(clap::Args, Debug, Clone)]
+pub(super) struct RegistryOptions {
+    /// Remote registries to use, tried in order when a package or tarball is missing (404) or a
+    /// registry fails (5xx). Defaults to the "registry" set in .npmrc files, then the npm registry
+    #[arg(short, long, value_delimiter = ',')]
+    pub(super) registry: Vec<String>,
+
+    /// Accept invalid TLS certificates from registries and proxies
+    #[arg(long)]
+    pub(super) insecure: bool,
+
+    /// Retries of failed requests: connection errors, timeouts, 408, 429 and 5xx responses.
+    /// Defaults to "fetch-retries" from .npmrc files, then 2
+    #[arg(long)]
+    pub(super) fetch_retries: Option<u32>,
+
+    /// Delay before the first retry in milliseconds, doubled on each retry.
+    /// Defaults to "fetch-retry-mintimeout" from .npmrc files, then 1000
+    #[arg(long)]
+    pub(super) fetch_retry_mintimeout: Option<u64>,
+
+    /// Maximum delay between retries in milliseconds, including delays asked with Retry-After.
+    /// Defaults to "fetch-retry-maxtimeout" from .npmrc files, then 60000
+    #[arg(long)]
+    pub(super) fetch_retry_maxtimeout: Option<u64>,
+
+    /// Timeout of a request, including reading its response, in milliseconds.
+    /// Defaults to "fetch-timeout" from .npmrc files, then 300000
+    #[arg(long)]
+    pub(super) fetch_timeout: Option<u64>,
+
+    /// Timeout to connect to a registry or proxy in milliseconds.
+    /// Defaults to "connect-timeout" from .npmrc files, then 10000
+    #[arg(long)]
+    pub(super) connect_timeout: Option<u64>,
+
+    /// Directory caching package manifests and tarballs. Defaults to "npm-offline" in the user
+    /// cache directory, or in the temporary directory when the user has none
+    #[arg(long)]
+    pub(super) cache: Option<PathBuf>,
+
+    /// Use cached package manifests without checking whether they are up to date
+    #[arg(long)]
+    pub(super) prefer_offline: bool,
+
+    /// Only use cached package manifests and already downloaded tarballs, without any request
+    #[arg(long, conflicts_with = "prefer_offline")]
+    pub(super) offline: bool,
+}
 
+/// When and how many times failed requests are retried
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    retries: u32,
+    min: Duration,
+    max: Duration,
+}
+
+/// Response of a registry, read entirely
+struct Fetched {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Registries packages are fetched from. Scoped packages go to the registry configured
+/// for their scope (`@company:registry=...`), others to the default ones, in order
+pub(super) struct Registries {
+    default: Vec<Registry>,
+    scoped: HashMap<String, Registry>,
+    store: Store,
+}
+
+/// Client of a single registry
 pub(super) struct Registry {
     client: reqwest::blocking::Client,
-    registry: String,
+    /// Registry URL, with a trailing slash so package paths are joined after its own path
+    url: Url,
+    /// Authorization of the registry, sent to its origin only (requests are always authorized,
+    /// as with `always-auth`). Tarballs served from other hosts get the credentials configured
+    /// for those hosts
+    auth: Option<HeaderValue>,
+    /// Authorizations configured for any registry, by URL prefix without scheme, longest first
+    credentials: Vec<(String, HeaderValue)>,
+    retry: Retry,
+    cache: Cache,
+    prefer_offline: bool,
+    offline: bool,
 }
 
-impl Registry {
-    pub(super) fn new(registry: Option<String>) -> Result<Self, CustomErrors> {
+impl Registries {
+    /// Create clients for the registries of the options, or the registry configured in .npmrc files,
+    /// and for every scoped registry of the configuration
+    pub(super) fn new(config: &Npmrc, options: &RegistryOptions) -> Result<Self, CustomErrors> {
+        let mut registries = options.registry.clone();
+        if registries.is_empty() {
+            registries.push(config.get("registry").unwrap_or(REGISTRY_URL).to_string());
+        }
+
+        // legacy credentials apply to the main registry
+        let default = parse_url(&registries[0])?;
+        let credentials = config
+            .credentials(&nerf_dart(&default))?
+            .into_iter()
+            .map(|(prefix, auth)| {
+                let mut auth = HeaderValue::from_str(&auth)
+                    .map_err(|e| CustomErrors::HttpHeaderParse(e.to_string()))?;
+                auth.set_sensitive(true);
+                Ok((prefix, auth))
+            })
+            .collect::<Result<Vec<_>, CustomErrors>>()?;
+
         Ok(Self {
-            client: reqwest::blocking::Client::builder()
-                .default_headers(headers!(
-                    (ACCEPT, "application/vnd.npm.install-v1+json"),
-                    (USER_AGENT, "npm-offline@v0.1.0")
+            default: registries
+                .iter()
+                .map(|url| Registry::new(config, options, url, &credentials))
+                .collect::<Result<_, _>>()?,
+            scoped: config
+                .scoped_registries()
+                .map(|(scope, url)| {
+                    Ok((
+                        scope.to_string(),
+                        Registry::new(config, options, url, &credentials)?,
+                    ))
+                })
+                .collect::<Result<_, CustomErrors>>()?,
+            store: Store::new(options.cache.as_deref()),
+        })
+    }
+
+    /// Registries serving a package, in the order they are tried
+    fn candidates(&self, package: &str) -> Vec<&Registry> {
+        let scoped = package
+            .split_once('/')
+            .filter(|(scope, _)| scope.starts_with('@'))
+            .and_then(|(scope, _)| self.scoped.get(scope));
+
+        match scoped {
+            Some(registry) => vec![registry],
+            None => self.default.iter().collect(),
+        }
+    }
+
+    /// Fetch a package manifest from the first registry having it. Returns the manifest along
+    /// with the URL of the registry which served it
+    pub(super) fn fetch_package(
+        &self,
+        package: &str,
+        full: bool,
+    ) -> Result<(PackageRsp, String), CustomErrors> {
+        let mut errors = vec![];
+        for registry in self.candidates(package) {
+            match registry.fetch_package(package, full) {
+                Ok(packument) => return Ok((packument, registry.url.to_string())),
+                Err(e) if falls_back(&e) => errors.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(exhausted(errors))
+    }
+
+    /// Response of the full manifest of a package, as kept in the cache, from the registry
+    /// which served it during the resolution (`source`). The manifest is fetched, or
+    /// revalidated, like any other
+    pub(super) fn packument_response(
+        &self,
+        package: &str,
+        source: Option<&str>,
+    ) -> Result<CachedResponse, CustomErrors> {
+        let candidates = self.candidates(package);
+        let registry = candidates
+            .iter()
+            .find(|r| Some(r.url.as_str()) == source)
+            .or(candidates.first())
+            .ok_or(CustomErrors::PackageNotFound(package.to_string()))?;
+
+        registry.fetch_package(package, true)?;
+        let (url, _, key) = registry.packument_request(package, true)?;
+        registry
+            .cache
+            .get(&key)
+            .ok_or(CustomErrors::PackageNotFound(format!(
+                "{package}: {url} isn't cached"
+            )))
+    }
+
+    /// Place a package tarball into `output`, from the shared store. `source` is the registry
+    /// which served the package manifest. Returns the path of the tarball along with its URL
+    pub(super) fn download_tarball(
+        &self,
+        package: &str,
+        source: Option<&str>,
+        dist: &Dist,
+        output: &Path,
+        link: LinkMethod,
+    ) -> Result<(String, String), CustomErrors> {
+        let integrity = Integrity::of(dist)?;
+        let filename = tarball_filename(&dist.tarball)?;
+
+        fs::create_dir_all(output).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        let file = output.join(&filename);
+        let path = file
+            .to_str()
+            .ok_or(CustomErrors::Global(
+                "failed to convert file path to string".to_string(),
+            ))?
+            .to_string();
+
+        if integrity.matches_file(&file) {
+            return Ok((path, dist.tarball.clone()));
+        }
+        if file.exists() {
+            println!("{}: checksum mismatch, replacing...", filename);
+        }
+
+        let (stored, url) = self.stored_tarball(package, source, dist)?;
+        store::link(&stored, &file, link)?;
+
+        Ok((path, url))
+    }
+
+    /// Path of a package tarball in the shared store. Missing tarballs are downloaded from
+    /// their `tarball` URL then from the same path on the other registries of the package,
+    /// and added to the store. Returns the stored path along with the URL of the tarball
+    pub(super) fn stored_tarball(
+        &self,
+        package: &str,
+        source: Option<&str>,
+        dist: &Dist,
+    ) -> Result<(PathBuf, String), CustomErrors> {
+        let integrity = Integrity::of(dist)?;
+        if let Some(stored) = self.store.get(&integrity) {
+            return Ok((stored, dist.tarball.clone()));
+        }
+
+        let filename = tarball_filename(&dist.tarball)?;
+        let (content, url) = self.fetch_tarball(package, source, &dist.tarball, &filename)?;
+
+        Ok((self.store.insert(&integrity, &content)?, url))
+    }
+
+    /// Download a tarball from the first registry having it
+    fn fetch_tarball(
+        &self,
+        package: &str,
+        source: Option<&str>,
+        tarball: &str,
+        filename: &str,
+    ) -> Result<(Vec<u8>, String), CustomErrors> {
+        let candidates = self.candidates(package);
+        let source = candidates
+            .iter()
+            .find(|r| Some(r.url.as_str()) == source)
+            .or(candidates.first())
+            .copied();
+
+        let mut attempts = source
+            .map(|r| (r, tarball.to_string()))
+            .into_iter()
+            .collect::<Vec<_>>();
+        for registry in candidates {
+            let Ok(url) = registry.url.join(&format!("{package}/-/{filename}")) else {
+                continue;
+            };
+            if !attempts.iter().any(|(_, u)| *u == url.as_str()) {
+                attempts.push((registry, url.to_string()));
+            }
+        }
+
+        let mut errors = vec![];
+        for (registry, url) in attempts {
+            match registry.fetch_tarball(&url) {
+                Ok(content) => return Ok((content, url)),
+                Err(e) if falls_back(&e) => errors.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(exhausted(errors))
+    }
+}
+
+/// Whether another registry is tried after an error: missing package or tarball, or failing registry
+fn falls_back(error: &CustomErrors) -> bool {
+    matches!(
+        error,
+        CustomErrors::PackageNotFound(_) | CustomErrors::Request(_)
+    )
+}
+
+/// Error returned once every registry failed
+fn exhausted(mut errors: Vec<CustomErrors>) -> CustomErrors {
+    if errors.len() == 1 {
+        return errors.remove(0);
+    }
+
+    CustomErrors::Global(format!(
+        "every registry failed: {}",
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    ))
+}
+
+impl Registry {
+    /// Create a client for a registry URL. TLS, proxy and user agent settings are read from the configuration
+    /// and the environment
+    fn new(
+        config: &Npmrc,
+        options: &RegistryOptions,
+        registry: &str,
+        credentials: &[(String, HeaderValue)],
+    ) -> Result<Self, CustomErrors> {
+        let user_agent = config.get("user-agent").unwrap_or(USER_AGENT_DEFAULT);
+        let setting = |option: Option<u64>, key: &str, default: u64| match option {
+            Some(value) => Ok(value),
+            None => config.number(key).map(|value| value.unwrap_or(default)),
+        };
+        let retry = Retry {
+            retries: setting(options.fetch_retries.map(u64::from), "fetch-retries", 2)? as u32,
+            min: Duration::from_millis(setting(
+                options.fetch_retry_mintimeout,
+                "fetch-retry-mintimeout",
+                1000,
+            )?),
+            max: Duration::from_millis(setting(
+                options.fetch_retry_maxtimeout,
+                "fetch-retry-maxtimeout",
+                60000,
+            )?),
+        };
+
+        let mut builder = reqwest::blocking::Client::builder()
+            .default_headers(headers!(
+                (ACCEPT, "application/vnd.npm.install-v1+json"),
+                (USER_AGENT, user_agent)
+            ))
+            .timeout(Duration::from_millis(setting(
+                options.fetch_timeout,
+                "fetch-timeout",
+                300000,
+            )?))
+            .connect_timeout(Duration::from_millis(setting(
+                options.connect_timeout,
+                "connect-timeout",
+                10000,
+            )?));
+
+        if options.insecure || config.flag("strict-ssl") == Some(false) {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(cafile) = config.get("cafile") {
+            for cert in read_certificates(Path::new(cafile))? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (config.get("certfile"), config.get("keyfile")) {
+            (Some(certfile), Some(keyfile)) => {
+                builder = builder.identity(read_identity(Path::new(certfile), Path::new(keyfile))?)
+            }
+            (None, None) => {}
+            _ => {
+                return Err(CustomErrors::HttpClient(
+                    "client certificates need both certfile and keyfile".to_string(),
                 ))
+            }
+        }
+
+        // proxies are configured explicitly so .npmrc settings take precedence over the environment
+        builder = builder.no_proxy();
+        let no_proxy = config
+            .get("noproxy")
+            .map(str::to_string)
+            .or_else(|| env_var(&["NO_PROXY", "no_proxy"]))
+            .and_then(|list| NoProxy::from_string(&list));
+        let https_proxy = config
+            .get("https-proxy")
+            .or(config.get("proxy"))
+            .map(str::to_string)
+            .or_else(|| env_var(&["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"]));
+        let http_proxy = config
+            .get("proxy")
+            .map(str::to_string)
+            .or_else(|| env_var(&["HTTP_PROXY", "http_proxy"]));
+
+        if let Some(proxy) = https_proxy {
+            builder = builder.proxy(
+                Proxy::https(&proxy)
+                    .map_err(|e| CustomErrors::HttpClient(format!("invalid proxy {proxy}: {e}")))?
+                    .no_proxy(no_proxy.clone()),
+            );
+        }
+        if let Some(proxy) = http_proxy {
+            builder = builder.proxy(
+                Proxy::http(&proxy)
+                    .map_err(|e| CustomErrors::HttpClient(format!("invalid proxy {proxy}: {e}")))?
+                    .no_proxy(no_proxy),
+            );
+        }
+
+        let mut url = parse_url(registry)?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+
+        let mut registry = Self {
+            client: builder
                 .build()
                 .map_err(|e| CustomErrors::HttpClient(e.to_string()))?,
-            registry: registry.unwrap_or_else(|| REGISTRY_URL.to_string()),
-        })
+            url: url.clone(),
+            auth: None,
+            credentials: credentials.to_vec(),
+            retry,
+            cache: Cache::new(options.cache.as_deref()),
+            prefer_offline: options.prefer_offline,
+            offline: options.offline,
+        };
+        registry.auth = registry.configured_auth(&url).cloned();
+
+        Ok(registry)
+    }
+
+    /// Authorization configured for the longest URL prefix matching `url`
+    fn configured_auth(&self, url: &Url) -> Option<&HeaderValue> {
+        let nerf = nerf_dart(url);
+        self.credentials
+            .iter()
+            .find(|(prefix, _)| nerf.starts_with(prefix.as_str()))
+            .map(|(_, auth)| auth)
+    }
+
+    /// Authorize a request to `url`, with the credentials configured for it or the registry ones
+    fn authorize(&self, req: RequestBuilder, url: &Url) -> RequestBuilder {
+        let auth = self.configured_auth(url).or(self
+            .auth
+            .as_ref()
+            .filter(|_| url.origin() == self.url.origin()));
+
+        match auth {
+            Some(auth) => req.header(AUTHORIZATION, auth.clone()),
+            None => req,
+        }
     }
 
-    /// Fetch package version and its dependencies from registry.
-    /// First entry in returned vector is the top-level package
-    pub(super) fn fetch_dependencies(
+    /// Send an authorized GET request and read its response. Connection errors, timeouts, 408, 429
+    /// and 5xx responses are retried with exponential backoff, or after the delay
+    /// given by a Retry-After header
+    fn fetch(&self, url: &Url, headers: HeaderMap) -> Result<Fetched, CustomErrors> {
+        if self.offline {
+            return Err(CustomErrors::PackageNotFound(format!(
+                "{url} isn't available offline"
+            )));
+        }
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+
+            let req = self
+                .authorize(self.client.get(url.clone()), url)
+                .headers(headers.clone());
+
+            let (error, retry_after) = match req.send() {
+                Ok(rsp) if retryable(rsp.status()) => {
+                    (rsp.status().to_string(), retry_after(rsp.headers()))
+                }
+                Ok(rsp) => {
+                    let status = rsp.status();
+                    let headers = rsp.headers().clone();
+                    match rsp.bytes() {
+                        Ok(body) => {
+                            return Ok(Fetched {
+                                status,
+                                headers,
+                                body: body.to_vec(),
+                            })
+                        }
+                        Err(e) => (e.to_string(), None),
+                    }
+                }
+                Err(e) if e.is_builder() || e.is_redirect() => {
+                    return Err(CustomErrors::Request(format!("GET {url}: {e}")))
+                }
+                Err(e) => (e.to_string(), None),
+            };
+
+            if attempt > self.retry.retries {
+                return Err(CustomErrors::Request(format!(
+                    "GET {url} failed after {attempt} attempt(s): {error}"
+                )));
+            }
+
+            let delay = self.retry.delay(attempt, retry_after);
+            println!(
+                "GET {url}: {error}, retrying in {}ms ({attempt}/{})",
+                delay.as_millis(),
+                self.retry.retries
+            );
+            thread::sleep(delay);
+        }
+    }
+
+    /// URL of a package manifest, along with the accept header and cache key of its request
+    fn packument_request(
         &self,
-        package: String,
-        version_req: Option<Vec<VersionReq>>,
-        dev: bool,
-        peer: bool,
-        optional: bool,
-        dispatch: bool,
-    ) -> Result<HashMap<String, HashMap<String, Version>>, CustomErrors> {
-        let rsp = self
-            .client
-            .get(format!("{}/{}", self.registry, package))
-            .send()
-            .map_err(|e| CustomErrors::PackageManifestFetch(e.to_string()))?;
-
-        if !rsp.status().is_success() {
+        package: &str,
+        full: bool,
+    ) -> Result<(Url, &'static str, String), CustomErrors> {
+        // scoped packages are requested as "@scope%2fname", which every registry understands
+        let url = self
+            .url
+            .join(&package.replace('/', "%2f"))
+            .map_err(|e| CustomErrors::Global(format!("invalid package name {package}: {e}")))?;
+        let accept = match full {
+            true => "application/json",
+            false => "application/vnd.npm.install-v1+json",
+        };
+        let key = format!("{accept} {url}");
+
+        Ok((url, accept, key))
+    }
+
+    /// Fetch package manifest (packument) from registry.
+    /// Abbreviated manifests are requested unless `full` is set
+    fn fetch_package(&self, package: &str, full: bool) -> Result<PackageRsp, CustomErrors> {
+        let (url, accept, key) = self.packument_request(package, full)?;
+        let cached = self.cache.get(&key);
+
+        if let Some(cached) = &cached {
+            if self.offline || self.prefer_offline {
+                return parse_packument(cached.body.as_bytes());
+            }
+        }
+        if self.offline {
+            return Err(CustomErrors::PackageNotFound(format!(
+                "{package} isn't cached for {} (--offline)",
+                self.url
+            )));
+        }
+
+        // cached manifests are revalidated, the registry answers 304 when they are up to date
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        if let Some(etag) = cached.as_ref().and_then(|c| c.etag.as_deref()) {
+            if let Ok(etag) = HeaderValue::from_str(etag) {
+                headers.insert(IF_NONE_MATCH, etag);
+            }
+        }
+        if let Some(modified) = cached.as_ref().and_then(|c| c.last_modified.as_deref()) {
+            if let Ok(modified) = HeaderValue::from_str(modified) {
+                headers.insert(IF_MODIFIED_SINCE, modified);
+            }
+        }
+
+        let rsp = self.fetch(&url, headers)?;
+        if let (StatusCode::NOT_MODIFIED, Some(cached)) = (rsp.status, &cached) {
+            return parse_packument(cached.body.as_bytes());
+        }
+
+        if rsp.status == StatusCode::NOT_FOUND {
+            return Err(CustomErrors::PackageNotFound(format!(
+                "{package} on {}",
+                self.url
+            )));
+        }
+        if !rsp.status.is_success() {
             return Err(CustomErrors::PackageManifestFetch(
-                rsp.json::<serde_json::Value>()
+                serde_json::from_slice::<serde_json::Value>(&rsp.body)
                     .map_err(|e| CustomErrors::BodyParse("JSON".to_string(), e.to_string()))?
                     .as_object()
                     .unwrap_or(&serde_json::Map::new())
@@ -69,124 +613,336 @@ impl Registry {
             ));
         }
 
-        let body = rsp
-            .json::<PackageRsp>()
-            .map_err(|e| CustomErrors::BodyParse("JSON".to_string(), e.to_string()))?;
-
-        let pkg_version = find_version(
-            body.versions,
-            version_req,
-            body.dist_tags.get("latest").map(|v| v.as_str()),
-        )?
-        .ok_or(CustomErrors::Version(format!(
-            "no version found for {package}@latest"
-        )))?;
+        let packument = parse_packument(&rsp.body)?;
 
-        let mut deps = hashmap!((
-            package,
-            hashmap!((pkg_version.version.clone(), pkg_version.clone()))
-        ));
-        for (dep, version) in hashmap_ext_cond!(
-            (true, pkg_version.dependencies),
-            (dev, pkg_version.dev_dependencies),
-            (peer, pkg_version.peer_dependencies),
-            (optional, pkg_version.optional_dependencies)
-        ) {
-            match parse(&version) {
-                Ok(v) => manuel_extend(
-                    self.fetch_dependencies(
-                        dep,
-                        Some(v),
-                        dev && dispatch,
-                        peer && dispatch,
-                        optional && dispatch,
-                        dispatch,
-                    )?,
-                    &mut deps,
-                ),
-                Err(e) => eprintln!("{dep}@{version}: failed to parse requirement version {e}"),
-            };
+        let header = |name| {
+            rsp.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let cached = CachedResponse {
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            body: String::from_utf8_lossy(&rsp.body).to_string(),
+        };
+        if let Err(e) = self.cache.put(&key, &cached) {
+            println!("{package}: failed to cache package manifest => {e}");
         }
 
-        Ok(deps)
+        Ok(packument)
     }
 
     /// Download dependency tarball from registry.
-    pub(super) fn download_tarball(
-        &self,
-        tarball_sum: String,
-        url: String,
-        output: &str,
-    ) -> Result<String, CustomErrors> {
-        let parsed_url = Url::parse(&url).map_err(|e| CustomErrors::Global(e.to_string()))?;
-        let filename = parsed_url
-            .path_segments()
-            .ok_or(CustomErrors::Global(format!(
-                "failed to parse path segments from url: {}",
-                url
-            )))?
-            .last()
-            .ok_or(CustomErrors::Global(format!(
-                "failed to get last path segment in url: {}",
-                url
-            )))?;
-
-        let res = self
-            .client
-            .get(url)
-            .send()
-            .map_err(|e| CustomErrors::PackageManifestFetch(e.to_string()))?;
-
-        let dir = Path::new(output);
-        if !dir.exists() {
-            create_dir(&dir).map_err(|e| CustomErrors::Fs(e.to_string()))?;
-        }
-        let file = dir.join(filename);
-
-        let mut hasher = Sha1::new();
-        if file.exists() {
-            let mut f = File::open(&file).map_err(|e| CustomErrors::Fs(e.to_string()))?;
-            io::copy(&mut f, &mut hasher).map_err(|e| CustomErrors::Fs(e.to_string()))?;
-
-            if base16ct::lower::encode_string(&hasher.finalize()) == tarball_sum {
-                return Ok(file
-                    .to_str()
-                    .ok_or(CustomErrors::Global(
-                        "failed to convert file path to string".to_string(),
-                    ))?
-                    .to_string());
-            }
+    fn fetch_tarball(&self, url: &str) -> Result<Vec<u8>, CustomErrors> {
+        let url = parse_url(url)?;
+        let res = self.fetch(&url, HeaderMap::new())?;
+        if res.status == StatusCode::NOT_FOUND {
+            return Err(CustomErrors::PackageNotFound(url.to_string()));
+        }
+        if !res.status.is_success() {
+            return Err(CustomErrors::Request(format!(
+                "GET {url} responded {}",
+                res.status
+            )));
+        }
+
+        Ok(res.body)
+    }
+}
 
-            println!("{}: checksum mismatch, redownloading...", filename);
+impl Retry {
+    /// Delay before a retry: the minimum delay doubled for each previous attempt, capped to
+    /// the maximum delay, of which a random half is removed so clients don't retry in sync
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max);
+        let jitter = RandomState::new().hash_one(attempt) % 1000;
+
+        delay / 2 + delay / 2 * jitter as u32 / 1000
+    }
+
+    /// Delay before a retry: the one asked by the registry (Retry-After) capped to the maximum
+    /// delay, or the backoff
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(delay) => delay.min(self.max),
+            None => self.backoff(attempt),
         }
+    }
+}
 
-        let data = res
-            .bytes()
-            .map_err(|e| CustomErrors::BodyParse("BYTES".to_string(), e.to_string()))?;
+fn parse_packument(body: &[u8]) -> Result<PackageRsp, CustomErrors> {
+    serde_json::from_slice::<PackageRsp>(body)
+        .map_err(|e| CustomErrors::BodyParse("JSON".to_string(), e.to_string()))
+}
 
-        let mut f = File::create(&file).map_err(|e| CustomErrors::Fs(e.to_string()))?;
-        f.write_all(&data)
-            .map_err(|e| CustomErrors::Fs(e.to_string()))?;
+/// Whether a response status is worth retrying
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
 
-        Ok(file
-            .to_str()
-            .ok_or(CustomErrors::Global(
-                "failed to convert file path to string".to_string(),
-            ))?
-            .to_string())
+/// Delay requested by a Retry-After header, either in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
     }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
-pub(super) fn manuel_extend(
-    src: HashMap<String, HashMap<String, Version>>,
-    dst: &mut HashMap<String, HashMap<String, Version>>,
-) {
-    for (k, v) in src {
-        if dst.contains_key(&k) {
-            let versions = dst.get_mut(&k).unwrap();
-            versions.extend(v);
-        } else {
-            dst.insert(k, v);
+/// Read every certificate of a PEM bundle
+fn read_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>, CustomErrors> {
+    let pem = fs::read_to_string(path)
+        .map_err(|e| CustomErrors::Fs(format!("failed to read {}: {e}", path.display())))?;
+
+    pem.split_inclusive("-----END CERTIFICATE-----")
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| {
+            reqwest::Certificate::from_pem(block.trim().as_bytes()).map_err(|e| {
+                CustomErrors::HttpClient(format!("invalid certificate in {}: {e}", path.display()))
+            })
+        })
+        .collect()
+}
+
+/// Read a client certificate and its PKCS #8 private key, both PEM encoded
+fn read_identity(certfile: &Path, keyfile: &Path) -> Result<Identity, CustomErrors> {
+    let read = |path: &Path| {
+        fs::read(path)
+            .map_err(|e| CustomErrors::Fs(format!("failed to read {}: {e}", path.display())))
+    };
+
+    Identity::from_pkcs8_pem(&read(certfile)?, &read(keyfile)?).map_err(|e| {
+        CustomErrors::HttpClient(format!(
+            "invalid client certificate {} or key {}: {e}",
+            certfile.display(),
+            keyfile.display()
+        ))
+    })
+}
+
+/// First non-empty environment variable among `names`
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()))
+}
+
+/// File name of a tarball, the last segment of its URL
+fn tarball_filename(tarball: &str) -> Result<String, CustomErrors> {
+    parse_url(tarball)?
+        .path_segments()
+        .and_then(|mut segments| segments.next_back().map(str::to_string))
+        .filter(|filename| !filename.is_empty())
+        .ok_or(CustomErrors::Global(format!(
+            "failed to get last path segment in url: {tarball}"
+        )))
+}
+
+fn parse_url(url: &str) -> Result<Url, CustomErrors> {
+    Url::parse(url).map_err(|e| CustomErrors::Global(format!("invalid URL \"{url}\": {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::JoinHandle,
+    };
+
+    use super::*;
+
+    const RETRY: Retry = Retry {
+        retries: 2,
+        min: Duration::from_millis(1000),
+        max: Duration::from_millis(60000),
+    };
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        for (attempt, full) in [(1, 1000), (2, 2000), (3, 4000), (7, 60000), (40, 60000)] {
+            let delay = RETRY.backoff(attempt).as_millis();
+            assert!(
+                (full / 2..=full).contains(&delay),
+                "attempt {attempt}: {delay}ms"
+            );
         }
     }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let day = Duration::from_secs(86400);
+        assert_eq!(RETRY.delay(1, Some(day)), RETRY.max);
+        assert_eq!(
+            RETRY.delay(1, Some(Duration::from_secs(2))),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    fn options() -> RegistryOptions {
+        RegistryOptions {
+            registry: vec![],
+            insecure: false,
+            fetch_retries: Some(0),
+            fetch_retry_mintimeout: None,
+            fetch_retry_maxtimeout: None,
+            fetch_timeout: Some(5000),
+            connect_timeout: Some(5000),
+            cache: Some(env::temp_dir().join("npm-offline-registry-tests")),
+            prefer_offline: false,
+            offline: false,
+        }
+    }
+
+    /// HTTP server answering a single request with a package manifest. Returns its port, and
+    /// the request line it received once joined
+    fn serve_once() -> (u16, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+
+            let body = r#"{"name":"pkg","versions":{}}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+
+            request_line.trim_end().to_string()
+        });
+
+        (port, handle)
+    }
+
+    /// Port nothing listens on
+    fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn requests_go_through_proxy() {
+        let (proxy, handle) = serve_once();
+        let config = Npmrc::from_ini(&format!("proxy=http://127.0.0.1:{proxy}/\n"));
+        let registry = Registry::new(&config, &options(), "http://registry.test/", &[]).unwrap();
+
+        let url = Url::parse("http://registry.test/pkg").unwrap();
+        let rsp = registry.fetch(&url, HeaderMap::new()).unwrap();
+
+        assert_eq!(rsp.status, StatusCode::OK);
+        // proxies receive absolute URIs
+        assert_eq!(
+            handle.join().unwrap(),
+            "GET http://registry.test/pkg HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn noproxy_hosts_bypass_proxy() {
+        let (port, handle) = serve_once();
+        let config = Npmrc::from_ini(&format!(
+            "proxy=http://127.0.0.1:{}/\nnoproxy=127.0.0.1\n",
+            closed_port()
+        ));
+        let registry = Registry::new(
+            &config,
+            &options(),
+            &format!("http://127.0.0.1:{port}/"),
+            &[],
+        )
+        .unwrap();
+
+        let url = registry.url.join("pkg").unwrap();
+        let rsp = registry.fetch(&url, HeaderMap::new()).unwrap();
+
+        assert_eq!(rsp.status, StatusCode::OK);
+        assert_eq!(handle.join().unwrap(), "GET /pkg HTTP/1.1");
+    }
+
+    #[test]
+    fn unreachable_proxy_fails() {
+        let config = Npmrc::from_ini(&format!("proxy=http://127.0.0.1:{}/\n", closed_port()));
+        let registry = Registry::new(&config, &options(), "http://registry.test/", &[]).unwrap();
+
+        let url = Url::parse("http://registry.test/pkg").unwrap();
+        assert!(matches!(
+            registry.fetch(&url, HeaderMap::new()),
+            Err(CustomErrors::Request(_))
+        ));
+    }
+
+    #[test]
+    fn credentials_stay_on_their_origin() {
+        let credentials = [
+            (
+                "//registry.test/".to_string(),
+                HeaderValue::from_static("Bearer internal"),
+            ),
+            (
+                "//cdn.test/".to_string(),
+                HeaderValue::from_static("Bearer cdn"),
+            ),
+        ];
+        let config = Npmrc::from_ini("always-auth=true\n");
+        let registry =
+            Registry::new(&config, &options(), "http://registry.test/", &credentials).unwrap();
+
+        let auth = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            registry
+                .authorize(registry.client.get(url.clone()), &url)
+                .build()
+                .unwrap()
+                .headers()
+                .get(AUTHORIZATION)
+                .cloned()
+        };
+        assert_eq!(
+            auth("http://registry.test/pkg/-/pkg-1.0.0.tgz").unwrap(),
+            "Bearer internal"
+        );
+        assert_eq!(auth("http://cdn.test/pkg-1.0.0.tgz").unwrap(), "Bearer cdn");
+        assert_eq!(auth("http://elsewhere.test/pkg-1.0.0.tgz"), None);
+    }
 }
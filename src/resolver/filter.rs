@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use semver::VersionReq;
+
+use crate::{errors::CustomErrors, utils::split_package_string, version::parse};
+
+/// Package pattern from `--filter`: a name glob (`@internal/*`), optionally followed by a range
+/// (`left-pad@<1.3`). Patterns prefixed with "!" exclude what they match
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    negated: bool,
+    name: glob::Pattern,
+    req: Option<Vec<VersionReq>>,
+}
+
+impl FromStr for Pattern {
+    type Err = CustomErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negated, pattern) = match s.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, s),
+        };
+
+        let (name, range) = split_package_string(pattern)?;
+        let name = glob::Pattern::new(&name)
+            .map_err(|e| CustomErrors::Global(format!("invalid filter \"{s}\": {e}")))?;
+        let req = if range == "latest" {
+            None
+        } else {
+            Some(parse(&range)?)
+        };
+
+        Ok(Self { negated, name, req })
+    }
+}
+
+impl Pattern {
+    fn matches_version(&self, version: &str) -> bool {
+        self.req.as_ref().is_none_or(|req| {
+            semver::Version::parse(version).is_ok_and(|v| req.iter().any(|r| r.matches(&v)))
+        })
+    }
+}
+
+/// Whether a package is left out entirely: excluded without range, or not matched by any
+/// pattern when some patterns include packages
+pub(crate) fn excluded(patterns: &[Pattern], name: &str) -> bool {
+    if patterns
+        .iter()
+        .any(|p| p.negated && p.req.is_none() && p.name.matches(name))
+    {
+        return true;
+    }
+
+    let mut included = patterns.iter().filter(|p| !p.negated).peekable();
+    included.peek().is_some() && !included.any(|p| p.name.matches(name))
+}
+
+/// Whether a version of a package can be resolved. When included with ranges only,
+/// versions must match one of them
+pub(crate) fn allowed(patterns: &[Pattern], name: &str, version: &str) -> bool {
+    let matching = patterns.iter().filter(|p| p.name.matches(name));
+    if matching
+        .clone()
+        .any(|p| p.negated && p.matches_version(version))
+    {
+        return false;
+    }
+
+    let mut included = matching.filter(|p| !p.negated).peekable();
+    included.peek().is_none() || included.any(|p| p.matches_version(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<Pattern> {
+        list.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn no_patterns_allow_everything() {
+        assert!(!excluded(&[], "left-pad"));
+        assert!(allowed(&[], "left-pad", "1.0.0"));
+    }
+
+    #[test]
+    fn exclusions() {
+        let patterns = patterns(&["!fsevents", "!left-pad@<1.3"]);
+
+        assert!(excluded(&patterns, "fsevents"));
+        // excluding versions keeps the package
+        assert!(!excluded(&patterns, "left-pad"));
+        assert!(!allowed(&patterns, "left-pad", "1.2.0"));
+        assert!(allowed(&patterns, "left-pad", "1.3.0"));
+        assert!(allowed(&patterns, "lodash", "4.0.0"));
+    }
+
+    #[test]
+    fn inclusions() {
+        let patterns = patterns(&["@internal/*", "lodash@^4", "!@internal/legacy"]);
+
+        assert!(!excluded(&patterns, "@internal/ui"));
+        assert!(excluded(&patterns, "@internal/legacy"));
+        assert!(excluded(&patterns, "left-pad"));
+        assert!(allowed(&patterns, "@internal/ui", "0.1.0"));
+        assert!(allowed(&patterns, "lodash", "4.17.21"));
+        assert!(!allowed(&patterns, "lodash", "3.10.1"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!("[".parse::<Pattern>().is_err());
+        assert!("left-pad@not a range".parse::<Pattern>().is_err());
+    }
+}
@@ -0,0 +1,998 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use chrono::{DateTime, Utc};
+use semver::VersionReq;
+
+use crate::{
+    errors::CustomErrors,
+    registry::Registries,
+    serde::{PackageRsp, Version},
+    utils::{find_version, nearest_versions, parse_date, parse_node_version},
+    version::parse,
+};
+
+pub(super) use self::overrides::Overrides;
+use self::{filter::Pattern, platform::Platform, tree::Tree};
+
+mod filter;
+mod overrides;
+mod platform;
+mod tree;
+
+/// Resolved packages, indexed by name then version
+pub(super) type Packages = HashMap<String, HashMap<String, Version>>;
+
+/// Requirements declared for each requested package
+pub(super) type PackageList = BTreeMap<String, HashSet<Requirement>>;
+
+/// Resolved package, as a `(name, version)` tuple
+pub(super) type Node = (String, String);
+
+/// Dependencies of a package, as `(name, requirement)` tuples
+type Dependencies = Vec<(String, Requirement)>;
+
+/// Dependency options shared by every command resolving a dependency graph
+#[derive(clap::Args, Debug, Clone)]
+pub(super) struct ResolveOptions {
+    /// Include devDependencies of inputs and their direct dependencies (same as --dev-depth 1)
+    #[arg(short = 'd', long)]
+    pub(super) dev_dependencies: bool,
+
+    /// Include peerDependencies of inputs and their direct dependencies (same as --peer-depth 1)
+    #[arg(long)]
+    pub(super) peer_dependencies: bool,
+
+    /// Include optionalDependencies of inputs and their direct dependencies (same as --optional-depth 1)
+    #[arg(long)]
+    pub(super) optional_dependencies: bool,
+
+    /// Include devDependencies of packages up to this depth, or "all".
+    /// Depth 0 is the package.json inputs, 1 their direct dependencies (or packages given by name)
+    #[arg(long, conflicts_with = "dev_dependencies")]
+    pub(super) dev_depth: Option<Depth>,
+
+    /// Include peerDependencies of packages up to this depth, or "all"
+    #[arg(long, conflicts_with = "peer_dependencies")]
+    pub(super) peer_depth: Option<Depth>,
+
+    /// Include optionalDependencies of packages up to this depth, or "all"
+    #[arg(long, conflicts_with = "optional_dependencies")]
+    pub(super) optional_depth: Option<Depth>,
+
+    /// Include required peerDependencies at every depth, like npm v7+. Peers marked optional in
+    /// peerDependenciesMeta are only included with --peer-dependencies
+    #[arg(long)]
+    pub(super) auto_peers: bool,
+
+    /// Platforms the bundle is installed on (linux-x64-glibc,win32-x64). When set, optionalDependencies
+    /// are included at every depth, keeping only those supporting one of the targets
+    #[arg(long = "target", value_delimiter = ',')]
+    pub(super) targets: Vec<Platform>,
+
+    /// Node.js version the bundle is installed with (16, 16.20 or v16.20.0). Versions whose
+    /// engines.node excludes it are skipped
+    #[arg(long, value_parser = parse_node_version)]
+    pub(super) node_version: Option<semver::Version>,
+
+    /// Only consider versions published before this date (2023-10-01 or 2023-10-01T12:00:00Z).
+    /// Fetches full package manifests, which are larger
+    #[arg(long, value_parser = parse_date)]
+    pub(super) before: Option<DateTime<Utc>>,
+
+    /// Packages allowed in the resolution (@internal/*,!fsevents,!left-pad@<1.3). Packages
+    /// excluded by "!" patterns are skipped without being fetched, and when patterns without "!"
+    /// are given, only packages matching one of them are resolved. Ranges restrict versions
+    #[arg(long = "filter", value_delimiter = ',')]
+    pub(super) filters: Vec<Pattern>,
+
+    /// Version picked for each range: the highest, the lowest, or the lowest for direct
+    /// dependencies only (to test against declared minimums)
+    #[arg(long, value_enum, default_value_t = Strategy::Highest)]
+    pub(super) strategy: Strategy,
+
+    /// Resolution mode. "npm" simulates npm v7+ node_modules placement, deduplicating
+    /// dependencies the way "npm install" would
+    #[arg(long, value_enum, default_value_t = ResolveMode::Independent)]
+    pub(super) mode: ResolveMode,
+}
+
+impl ResolveOptions {
+    /// Depth up to which dependencies of a kind are walked
+    fn depth(&self, kind: DependencyKind) -> Option<Depth> {
+        let (flag, depth) = match kind {
+            DependencyKind::Prod => return Some(Depth::All),
+            DependencyKind::Dev => (self.dev_dependencies, self.dev_depth),
+            DependencyKind::Peer => (self.peer_dependencies, self.peer_depth),
+            DependencyKind::Optional => (self.optional_dependencies, self.optional_depth),
+        };
+
+        depth.or(flag.then_some(Depth::Limited(1)))
+    }
+
+    /// Whether dependencies of a kind are walked for packages at `depth`
+    pub(super) fn includes(&self, kind: DependencyKind, depth: usize) -> bool {
+        match self.depth(kind) {
+            Some(Depth::All) => true,
+            Some(Depth::Limited(limit)) => depth <= limit,
+            None => false,
+        }
+    }
+
+    /// Depth past which policies don't change anymore
+    fn max_depth(&self) -> usize {
+        [
+            DependencyKind::Dev,
+            DependencyKind::Peer,
+            DependencyKind::Optional,
+        ]
+        .into_iter()
+        .filter_map(|kind| match self.depth(kind) {
+            Some(Depth::Limited(limit)) => Some(limit + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+    }
+}
+
+/// Depth of the dependency graph up to which a dependency kind is walked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Depth {
+    Limited(usize),
+    All,
+}
+
+impl FromStr for Depth {
+    type Err = CustomErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Depth::All);
+        }
+
+        s.parse().map(Depth::Limited).map_err(|_| {
+            CustomErrors::Global(format!(
+                "invalid depth \"{s}\", expected a number or \"all\""
+            ))
+        })
+    }
+}
+
+/// Which version satisfying a range is picked
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Strategy {
+    Highest,
+    Lowest,
+    LowestDirect,
+}
+
+/// How versions are picked for each requested range
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ResolveMode {
+    /// Pick a version for every requesting range independently
+    Independent,
+    /// Hoist and deduplicate packages like npm v7+, preferring already placed versions
+    Npm,
+}
+
+/// Map in which a dependency is declared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(super) enum DependencyKind {
+    Prod,
+    Dev,
+    Peer,
+    Optional,
+}
+
+impl fmt::Display for DependencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DependencyKind::Prod => "dependencies",
+            DependencyKind::Dev => "devDependencies",
+            DependencyKind::Peer => "peerDependencies",
+            DependencyKind::Optional => "optionalDependencies",
+        })
+    }
+}
+
+/// Version requirement on a package, as declared and parsed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct Requirement {
+    pub(super) range: String,
+    /// `None` means the "latest" dist-tag
+    pub(super) req: Option<Vec<VersionReq>>,
+    pub(super) kind: DependencyKind,
+}
+
+impl Requirement {
+    /// Whether a version satisfies the requirement. "latest" is satisfied by any version
+    pub(super) fn matches(&self, version: &str) -> bool {
+        let Some(req) = &self.req else {
+            return true;
+        };
+
+        semver::Version::parse(version).is_ok_and(|v| req.iter().any(|r| r.matches(&v)))
+    }
+
+    pub(super) fn parse(range: &str, kind: DependencyKind) -> Result<Self, CustomErrors> {
+        Ok(Self {
+            range: range.to_string(),
+            req: if range == "latest" {
+                None
+            } else {
+                Some(parse(range)?)
+            },
+            kind,
+        })
+    }
+}
+
+/// Link from a package (or an input when `from` is `None`) to one of its resolved dependencies
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(super) struct Edge {
+    pub(super) from: Option<Node>,
+    pub(super) to: Node,
+    pub(super) range: String,
+    pub(super) kind: DependencyKind,
+}
+
+/// Outcome of a resolution: every package to download and how they were reached
+#[derive(Debug, Default)]
+pub(super) struct Resolution {
+    pub(super) packages: Packages,
+    pub(super) edges: BTreeSet<Edge>,
+    /// Problems that didn't prevent the resolution
+    pub(super) warnings: Vec<String>,
+    /// Dependencies which couldn't be resolved
+    pub(super) failures: Vec<String>,
+    /// Registry each package manifest was fetched from
+    pub(super) sources: HashMap<String, String>,
+}
+
+impl Resolution {
+    /// List every path from an input to the given package. Each path starts with the input edge.
+    pub(super) fn paths_to(&self, node: &Node) -> Vec<Vec<&Edge>> {
+        let mut paths = vec![];
+        self.walk_back(node, &mut vec![], &mut paths);
+
+        paths
+    }
+
+    fn walk_back<'a>(
+        &'a self,
+        node: &Node,
+        current: &mut Vec<&'a Edge>,
+        paths: &mut Vec<Vec<&'a Edge>>,
+    ) {
+        for edge in self.edges.iter().filter(|e| &e.to == node) {
+            match &edge.from {
+                None => {
+                    let mut path = current.clone();
+                    path.push(edge);
+                    path.reverse();
+                    paths.push(path);
+                }
+                Some(from) => {
+                    // skip cycles
+                    if current.iter().any(|e| &e.to == from) || from == node {
+                        continue;
+                    }
+
+                    current.push(edge);
+                    self.walk_back(from, current, paths);
+                    current.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Packages requested by the user, along with the rules applying to their resolution
+#[derive(Debug, Default)]
+pub(super) struct Inputs {
+    pub(super) packages: PackageList,
+    pub(super) overrides: Overrides,
+}
+
+/// State carried down the dependency graph
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Context {
+    /// Depth of the package, capped once depth policies don't change anymore
+    depth: usize,
+    /// Whether the package is an optional dependency or part of one's dependencies,
+    /// in which case failures are tolerated
+    within_optional: bool,
+    /// Override scopes active for the package's dependencies
+    scopes: Vec<usize>,
+}
+
+/// Walk the dependency graph of a set of inputs through a registry
+pub(super) struct Resolver<'a> {
+    registries: &'a Registries,
+    options: &'a ResolveOptions,
+    overrides: Overrides,
+    packuments: HashMap<String, PackageRsp>,
+    /// Packages already walked, along with the context they were walked with
+    visited: HashSet<(Node, Context)>,
+    /// Tree nodes queued in npm mode, along with the context they were queued with
+    placed: HashSet<(usize, Context)>,
+    /// Peers walked again on revisits, as (dependent, peer) tuples
+    peers: HashSet<(Node, String)>,
+    /// Peers installed automatically next to their dependent, by parent of the dependent
+    /// (`None` for inputs) and peer name, along with the version and dependent
+    auto_peers: HashMap<(Option<Node>, String), (String, Node)>,
+    resolution: Resolution,
+}
+
+impl<'a> Resolver<'a> {
+    pub(super) fn new(registries: &'a Registries, options: &'a ResolveOptions) -> Self {
+        Self {
+            registries,
+            options,
+            overrides: Overrides::default(),
+            packuments: HashMap::new(),
+            visited: HashSet::new(),
+            placed: HashSet::new(),
+            peers: HashSet::new(),
+            auto_peers: HashMap::new(),
+            resolution: Resolution::default(),
+        }
+    }
+
+    /// Resolve all inputs and their dependencies
+    pub(super) fn resolve(mut self, inputs: Inputs) -> Result<Resolution, CustomErrors> {
+        self.overrides = inputs.overrides;
+
+        let ctx = Context {
+            depth: 1.min(self.options.max_depth()),
+            within_optional: false,
+            scopes: vec![Overrides::ROOT],
+        };
+        // requirements of a package are walked in a stable order, by kind then range
+        let packages = inputs
+            .packages
+            .into_iter()
+            .flat_map(|(name, reqs)| {
+                let mut reqs = reqs.into_iter().collect::<Vec<_>>();
+                reqs.sort_by(|a, b| (a.kind, &a.range).cmp(&(b.kind, &b.range)));
+                reqs.into_iter().map(move |r| (name.clone(), r))
+            })
+            .collect::<Vec<_>>();
+
+        if self.options.mode == ResolveMode::Npm {
+            return self.resolve_tree(packages, ctx);
+        }
+
+        let provided = index_provided(&packages);
+        for (name, requirement) in &packages {
+            println!("{name}: Resolving dependencies...");
+            self.visit(None, name.clone(), requirement, ctx.clone(), &provided)?;
+            println!("{name}: Resolved");
+        }
+
+        self.finish()
+    }
+
+    /// Resolve a package and walk its dependencies. `ctx` holds the package's flags
+    /// and the override scopes active where it is declared, `provided` the dependencies
+    /// declared alongside the package, used to satisfy its peerDependencies.
+    /// Returns the resolved package, if any
+    fn visit(
+        &mut self,
+        parent: Option<Node>,
+        package: String,
+        requirement: &Requirement,
+        mut ctx: Context,
+        provided: &HashMap<String, Requirement>,
+    ) -> Result<Option<Node>, CustomErrors> {
+        let direct = parent.is_none();
+        if self.excluded(&package) {
+            return Ok(None);
+        }
+        ctx.within_optional |= requirement.kind == DependencyKind::Optional;
+        let (requirement, scopes) =
+            match self.apply_overrides(&ctx.scopes, &package, requirement, direct) {
+                Ok(applied) => applied,
+                Err(e) => {
+                    self.fail(parent.as_ref(), &package, requirement, e, &ctx);
+                    return Ok(None);
+                }
+            };
+        ctx.scopes = scopes;
+
+        let pkg_version = match self.find(&package, &requirement, direct) {
+            Ok(v) => v,
+            Err(e) => {
+                self.fail(parent.as_ref(), &package, &requirement, e, &ctx);
+                return Ok(None);
+            }
+        };
+        if requirement.kind == DependencyKind::Optional && !self.supported(&pkg_version) {
+            return Ok(None);
+        }
+        let node = (package, pkg_version.version.clone());
+
+        self.resolution.edges.insert(Edge {
+            from: parent.clone(),
+            to: node.clone(),
+            range: requirement.range.clone(),
+            kind: requirement.kind,
+        });
+
+        // peers depend on what the dependent provides, they are walked on every visit
+        let revisit = !self.visited.insert((node.clone(), ctx.clone()));
+
+        self.resolution
+            .packages
+            .entry(node.0.clone())
+            .or_default()
+            .insert(node.1.clone(), pkg_version.clone());
+
+        let sub_ctx = self.sub_context(&ctx);
+        let deps = self.dependencies(&pkg_version, &ctx);
+        let sub_provided = index_provided(&deps);
+        for (dep, r) in deps {
+            if revisit && r.kind != DependencyKind::Peer {
+                continue;
+            }
+            if r.kind == DependencyKind::Peer
+                && (self.provide_peer(&node, &dep, &r, provided)
+                    || self.share_peer(parent.as_ref(), &node, &dep, &r))
+            {
+                continue;
+            }
+            // unprovided peers resolve the same way on every visit
+            if revisit && !self.peers.insert((node.clone(), dep.clone())) {
+                continue;
+            }
+
+            let resolved = self.visit(
+                Some(node.clone()),
+                dep.clone(),
+                &r,
+                sub_ctx.clone(),
+                &sub_provided,
+            )?;
+            // peers are installed next to their dependent, shared with its siblings
+            if let (DependencyKind::Peer, Some((_, version))) = (r.kind, resolved) {
+                self.auto_peers
+                    .entry((parent.clone(), dep))
+                    .or_insert((version, node.clone()));
+            }
+        }
+
+        Ok(Some(node))
+    }
+
+    /// Satisfy a peer dependency of `node` with the version its dependent provides.
+    /// Returns whether the peer was handled, either linked or reported as conflicting
+    fn provide_peer(
+        &mut self,
+        node: &Node,
+        peer: &str,
+        requirement: &Requirement,
+        provided: &HashMap<String, Requirement>,
+    ) -> bool {
+        let Some(provider) = provided
+            .get(peer)
+            .filter(|p| p.kind != DependencyKind::Peer)
+        else {
+            return false;
+        };
+        if filter::excluded(&self.options.filters, peer) {
+            return false;
+        }
+        let Ok(version) = self.find(peer, provider, false) else {
+            return false;
+        };
+
+        if !requirement.matches(&version.version) {
+            self.warn(format!(
+                "{}@{}: peer dependency {peer}@{} conflicts with {peer}@{} provided by its dependent",
+                node.0, node.1, requirement.range, version.version
+            ));
+            return true;
+        }
+
+        self.resolution.edges.insert(Edge {
+            from: Some(node.clone()),
+            to: (peer.to_string(), version.version),
+            range: requirement.range.clone(),
+            kind: requirement.kind,
+        });
+
+        true
+    }
+
+    /// Satisfy a peer dependency of `node` with the version already installed for the peers of
+    /// a sibling (a package sharing its `parent`), as npm would place a single copy next to them.
+    /// Returns whether the peer was handled, either linked or reported as conflicting
+    fn share_peer(
+        &mut self,
+        parent: Option<&Node>,
+        node: &Node,
+        peer: &str,
+        requirement: &Requirement,
+    ) -> bool {
+        let Some((version, dependent)) = self
+            .auto_peers
+            .get(&(parent.cloned(), peer.to_string()))
+            .cloned()
+        else {
+            return false;
+        };
+
+        if !requirement.matches(&version) {
+            self.warn(format!(
+                "{}@{}: peer dependency {peer}@{} conflicts with {peer}@{version} installed for {}@{}",
+                node.0, node.1, requirement.range, dependent.0, dependent.1
+            ));
+            return true;
+        }
+
+        self.resolution.edges.insert(Edge {
+            from: Some(node.clone()),
+            to: (peer.to_string(), version),
+            range: requirement.range.clone(),
+            kind: requirement.kind,
+        });
+
+        true
+    }
+
+    /// Resolve inputs by simulating npm's node_modules placement. The tree is built breadth-first
+    /// so shallow dependencies get hoisted first, and each dependency reuses the version visible
+    /// from its dependent when it satisfies the requested range.
+    fn resolve_tree<I>(mut self, inputs: I, ctx: Context) -> Result<Resolution, CustomErrors>
+    where
+        I: IntoIterator<Item = (String, Requirement)>,
+    {
+        let mut tree = Tree::default();
+        let mut roots: Vec<usize> = vec![];
+        let mut queue = VecDeque::new();
+
+        for (name, requirement) in inputs {
+            println!("{name}: Resolving dependencies...");
+
+            // conflicting inputs can't share the same node_modules. Roots are matched against
+            // the overridden requirement, failures are reported once placed
+            let overridden = self
+                .apply_overrides(&ctx.scopes, &name, &requirement, true)
+                .map_or_else(|_| requirement.clone(), |(r, _)| r);
+            let root = match roots.iter().copied().find(|&r| {
+                tree.child(r, &name)
+                    .is_none_or(|c| tree.package(c).is_some_and(|(_, v)| overridden.matches(v)))
+            }) {
+                Some(root) => root,
+                None => {
+                    roots.push(tree.add_root());
+                    roots[roots.len() - 1]
+                }
+            };
+
+            self.place(&mut tree, &mut queue, root, name, &requirement, ctx.clone())?;
+        }
+
+        while let Some((idx, ctx)) = queue.pop_front() {
+            let (name, version) = tree.package(idx).cloned().expect("roots are never queued");
+            let pkg_version = self.resolution.packages[&name][&version].clone();
+
+            let sub_ctx = self.sub_context(&ctx);
+            for (dep, r) in self.dependencies(&pkg_version, &ctx) {
+                self.place(&mut tree, &mut queue, idx, dep, &r, sub_ctx.clone())?;
+            }
+        }
+
+        println!("Dependency tree resolved");
+
+        self.finish()
+    }
+
+    /// Resolve a dependency of tree node `from`, placing a new package when no visible version satisfies it
+    fn place(
+        &mut self,
+        tree: &mut Tree,
+        queue: &mut VecDeque<(usize, Context)>,
+        from: usize,
+        package: String,
+        requirement: &Requirement,
+        mut ctx: Context,
+    ) -> Result<(), CustomErrors> {
+        let parent = tree.package(from).cloned();
+        let direct = parent.is_none();
+        if self.excluded(&package) {
+            return Ok(());
+        }
+        ctx.within_optional |= requirement.kind == DependencyKind::Optional;
+        let (requirement, scopes) =
+            match self.apply_overrides(&ctx.scopes, &package, requirement, direct) {
+                Ok(applied) => applied,
+                Err(e) => {
+                    self.fail(parent.as_ref(), &package, requirement, e, &ctx);
+                    return Ok(());
+                }
+            };
+        ctx.scopes = scopes;
+
+        // peers must be satisfied by the version visible from their dependent
+        if requirement.kind == DependencyKind::Peer {
+            if let Some((_, version)) = tree
+                .lookup(from, &package)
+                .and_then(|idx| tree.package(idx))
+                .filter(|(_, v)| !requirement.matches(v))
+            {
+                let requester = match &parent {
+                    Some((name, from_version)) => format!("{name}@{from_version}"),
+                    None => "inputs".to_string(),
+                };
+                self.warn(format!(
+                    "{requester}: peer dependency {package}@{} conflicts with {package}@{version}",
+                    requirement.range
+                ));
+                return Ok(());
+            }
+        }
+
+        let existing = tree.lookup(from, &package).filter(|&idx| {
+            tree.package(idx)
+                .is_some_and(|(_, v)| requirement.matches(v))
+        });
+        // already declared by `from` under another dependency kind
+        let existing = existing.or_else(|| tree.child(from, &package));
+
+        let idx = match existing {
+            // reused packages are walked again when reached with another context, failures
+            // below a package that is also required aren't tolerated as optional
+            Some(idx) => {
+                if self.placed.insert((idx, ctx.clone())) {
+                    queue.push_back((idx, ctx));
+                }
+                idx
+            }
+            None => {
+                let placed = tree
+                    .versions(&package)
+                    .filter(|v| requirement.matches(v))
+                    .filter_map(|v| semver::Version::parse(v).ok());
+                let placed = if self.lowest(direct) {
+                    placed.min()
+                } else {
+                    placed.max()
+                };
+
+                let pkg_version = match placed {
+                    Some(v) => self.resolution.packages[&package][&v.to_string()].clone(),
+                    None => match self.find(&package, &requirement, direct) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            self.fail(parent.as_ref(), &package, &requirement, e, &ctx);
+                            return Ok(());
+                        }
+                    },
+                };
+                if requirement.kind == DependencyKind::Optional && !self.supported(&pkg_version) {
+                    return Ok(());
+                }
+
+                let target = tree.placement(from, &package);
+                let idx = tree.place(target, (package.clone(), pkg_version.version.clone()));
+
+                self.resolution
+                    .packages
+                    .entry(package.clone())
+                    .or_default()
+                    .insert(pkg_version.version.clone(), pkg_version);
+                self.placed.insert((idx, ctx.clone()));
+                queue.push_back((idx, ctx));
+
+                idx
+            }
+        };
+
+        tree.link(from, &package, idx);
+        self.resolution.edges.insert(Edge {
+            from: parent,
+            to: tree
+                .package(idx)
+                .cloned()
+                .expect("placed nodes hold a package"),
+            range: requirement.range.clone(),
+            kind: requirement.kind,
+        });
+
+        Ok(())
+    }
+
+    /// Whether a package version supports one of the targeted platforms. Any version is
+    /// supported when no platform is targeted
+    fn supported(&self, version: &Version) -> bool {
+        self.options.targets.is_empty() || self.options.targets.iter().any(|t| t.supports(version))
+    }
+
+    /// Context applied to the dependencies of a package resolved with `ctx`
+    fn sub_context(&self, ctx: &Context) -> Context {
+        Context {
+            depth: (ctx.depth + 1).min(self.options.max_depth()),
+            within_optional: ctx.within_optional,
+            scopes: ctx.scopes.clone(),
+        }
+    }
+
+    /// Apply override rules active in `scopes` to a dependency. Returns the requirement to resolve
+    /// and the scopes active within the dependency's own dependencies
+    fn apply_overrides(
+        &mut self,
+        scopes: &[usize],
+        package: &str,
+        requirement: &Requirement,
+        direct: bool,
+    ) -> Result<(Requirement, Vec<usize>), CustomErrors> {
+        // innermost scopes take precedence
+        let rules = scopes
+            .iter()
+            .rev()
+            .flat_map(|&s| self.overrides.rules(s))
+            .filter(|r| r.name == package)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if rules.is_empty() {
+            return Ok((requirement.clone(), scopes.to_vec()));
+        }
+
+        // selectors with a range match the version resolved without overrides
+        let candidate = if rules.iter().any(|r| r.selector.is_some()) {
+            self.find(package, requirement, direct)
+                .ok()
+                .map(|v| v.version)
+        } else {
+            None
+        };
+
+        let mut overridden = None;
+        let mut sub_scopes = scopes.to_vec();
+        for rule in rules
+            .iter()
+            .filter(|r| r.selects(package, candidate.as_deref()))
+        {
+            if let (None, Some(spec)) = (&overridden, &rule.spec) {
+                overridden = Some(Requirement::parse(spec, requirement.kind)?);
+            }
+            if let Some(scope) = rule.scope {
+                sub_scopes.push(scope);
+            }
+        }
+
+        Ok((
+            overridden.unwrap_or_else(|| requirement.clone()),
+            sub_scopes,
+        ))
+    }
+
+    /// Whether the lowest satisfying version is picked for a dependency
+    fn lowest(&self, direct: bool) -> bool {
+        match self.options.strategy {
+            Strategy::Highest => false,
+            Strategy::Lowest => true,
+            Strategy::LowestDirect => direct,
+        }
+    }
+
+    /// Find the version of a package matching a requirement, following the resolution strategy.
+    /// `direct` is set for dependencies requested by the inputs. Package manifests are fetched once.
+    fn find(
+        &mut self,
+        package: &str,
+        requirement: &Requirement,
+        direct: bool,
+    ) -> Result<Version, CustomErrors> {
+        let req = requirement.req.clone();
+        if !self.packuments.contains_key(package) {
+            let (mut packument, source) = self
+                .registries
+                .fetch_package(package, self.options.before.is_some())?;
+            self.resolution.sources.insert(package.to_string(), source);
+
+            // versions without a publication date can't be proven older than the cutoff
+            if let Some(before) = self.options.before {
+                let time = &packument.time;
+                packument.versions.retain(|v, _| {
+                    time.get(v)
+                        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                        .is_some_and(|t| t < before)
+                });
+            }
+
+            packument
+                .versions
+                .retain(|v, _| filter::allowed(&self.options.filters, package, v));
+
+            self.packuments.insert(package.to_string(), packument);
+        }
+        let packument = &self.packuments[package];
+
+        let latest = if req.is_none() {
+            packument.dist_tags.get("latest").map(|v| v.as_str())
+        } else {
+            None
+        };
+
+        let node = self.options.node_version.as_ref();
+        let lowest = self.lowest(direct);
+        if let Some(version) = find_version(&packument.versions, req.clone(), latest, node, lowest)?
+        {
+            return Ok(version);
+        }
+
+        let Some(version) = find_version(&packument.versions, req.clone(), latest, None, lowest)?
+        else {
+            let nearest = nearest_versions(&packument.versions, req.as_deref(), 4);
+            return Err(CustomErrors::Version(format!(
+                "no version of {package} matches \"{}\", nearest available versions: {}",
+                requirement.range,
+                if nearest.is_empty() {
+                    "none".to_string()
+                } else {
+                    nearest.join(", ")
+                }
+            )));
+        };
+        if let Some(node) = node {
+            self.warn(format!(
+                "{package}@{}: no version compatible with node {node}, engines.node requires {}",
+                version.version,
+                version.engines.get("node").map_or("*", String::as_str)
+            ));
+        }
+
+        Ok(version)
+    }
+
+    /// Whether a package is excluded by the filters, in which case it's reported and skipped
+    fn excluded(&mut self, package: &str) -> bool {
+        if !filter::excluded(&self.options.filters, package) {
+            return false;
+        }
+
+        self.warn(format!("{package}: excluded by filters, skipped"));
+        true
+    }
+
+    /// Complete the resolution, failing when any dependency couldn't be resolved
+    fn finish(self) -> Result<Resolution, CustomErrors> {
+        if !self.resolution.failures.is_empty() {
+            return Err(CustomErrors::Resolution(self.resolution.failures));
+        }
+
+        Ok(self.resolution)
+    }
+
+    /// Record a dependency which couldn't be resolved, along with the package requesting it
+    /// Failures within optional dependencies are only reported as warnings
+    fn fail(
+        &mut self,
+        parent: Option<&Node>,
+        package: &str,
+        requirement: &Requirement,
+        error: CustomErrors,
+        ctx: &Context,
+    ) {
+        let requester = match parent {
+            Some((name, version)) => format!("{name}@{version}"),
+            None => "inputs".to_string(),
+        };
+        let failure = format!(
+            "{package}@{} ({} of {requester}): {error}",
+            requirement.range, requirement.kind
+        );
+        self.tolerate(failure, ctx.within_optional);
+    }
+
+    /// Record a failure, or only warn about it within optional dependencies
+    fn tolerate(&mut self, failure: String, within_optional: bool) {
+        if within_optional {
+            self.warn(format!("{failure}, skipped as optional"));
+        } else if !self.resolution.failures.contains(&failure) {
+            self.resolution.failures.push(failure);
+        }
+    }
+
+    /// Dependencies of a package version to walk. Unparsable requirements fail the resolution,
+    /// unless optional or within an optional dependency
+    fn dependencies(&mut self, version: &Version, ctx: &Context) -> Dependencies {
+        let (deps, invalid) = dependencies(version, ctx, self.options);
+        for (failure, optional) in invalid {
+            self.tolerate(failure, ctx.within_optional || optional);
+        }
+
+        deps
+    }
+
+    /// Record a warning, once
+    fn warn(&mut self, warning: String) {
+        if !self.resolution.warnings.contains(&warning) {
+            self.resolution.warnings.push(warning);
+        }
+    }
+}
+
+/// Dependencies of a package version to walk, sorted by kind then name, along with failures of
+/// unparsable requirements, which are skipped, and whether they are optional. Bundled dependencies ship within
+/// the package tarball and are never walked. With `auto_peers`, required peers are walked
+/// regardless of the context, as are optional dependencies when targeting platforms
+fn dependencies(
+    version: &Version,
+    ctx: &Context,
+    options: &ResolveOptions,
+) -> (Dependencies, Vec<(String, bool)>) {
+    let mut walked = vec![];
+    let mut invalid = vec![];
+    let bundled = version.bundled();
+
+    for (cond, kind, deps) in [
+        (true, DependencyKind::Prod, &version.dependencies),
+        (
+            options.includes(DependencyKind::Dev, ctx.depth),
+            DependencyKind::Dev,
+            &version.dev_dependencies,
+        ),
+        (
+            options.includes(DependencyKind::Peer, ctx.depth) || options.auto_peers,
+            DependencyKind::Peer,
+            &version.peer_dependencies,
+        ),
+        (
+            options.includes(DependencyKind::Optional, ctx.depth) || !options.targets.is_empty(),
+            DependencyKind::Optional,
+            &version.optional_dependencies,
+        ),
+    ] {
+        if !cond {
+            continue;
+        }
+
+        let mut deps = deps.iter().collect::<Vec<_>>();
+        deps.sort();
+
+        for (dep, range) in deps {
+            if kind != DependencyKind::Dev && bundled.contains(dep.as_str()) {
+                continue;
+            }
+            if kind == DependencyKind::Peer
+                && !options.includes(DependencyKind::Peer, ctx.depth)
+                && version.is_optional_peer(dep)
+            {
+                continue;
+            }
+
+            match Requirement::parse(range, kind) {
+                Ok(r) => walked.push((dep.clone(), r)),
+                Err(e) => invalid.push((
+                    format!(
+                        "{dep}@{range} ({kind} of {}@{}): failed to parse requirement version {e}",
+                        version.name, version.version
+                    ),
+                    kind == DependencyKind::Optional,
+                )),
+            };
+        }
+    }
+
+    (walked, invalid)
+}
+
+/// Index dependencies declared together by name, the first declaration winning
+fn index_provided(deps: &[(String, Requirement)]) -> HashMap<String, Requirement> {
+    let mut provided = HashMap::new();
+    for (name, requirement) in deps {
+        provided
+            .entry(name.clone())
+            .or_insert_with(|| requirement.clone());
+    }
+
+    provided
+}
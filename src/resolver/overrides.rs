@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+
+use semver::VersionReq;
+
+use crate::{
+    errors::CustomErrors, serde::OverrideValue, utils::split_package_string, version::parse,
+};
+
+/// Override rules from npm `overrides` and Yarn `resolutions`.
+/// Rules are stored in scopes: scope 0 applies to the whole graph, while nested scopes
+/// only apply within the dependencies of the package selecting them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Overrides {
+    scopes: Vec<Vec<Rule>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Rule {
+    pub(crate) name: String,
+    /// Versions of the package the rule applies to. `None` matches any version
+    pub(crate) selector: Option<Vec<VersionReq>>,
+    /// Replacement range for the package
+    pub(crate) spec: Option<String>,
+    /// Scope holding the rules applied within the package's dependencies
+    pub(crate) scope: Option<usize>,
+}
+
+impl Default for Overrides {
+    fn default() -> Self {
+        Self {
+            scopes: vec![vec![]],
+        }
+    }
+}
+
+impl Rule {
+    /// Whether the rule selects a package version
+    pub(crate) fn selects(&self, name: &str, version: Option<&str>) -> bool {
+        if self.name != name {
+            return false;
+        }
+
+        match (&self.selector, version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(selector), Some(version)) => semver::Version::parse(version)
+                .is_ok_and(|v| selector.iter().any(|r| r.matches(&v))),
+        }
+    }
+}
+
+impl Overrides {
+    pub(crate) const ROOT: usize = 0;
+
+    pub(crate) fn rules(&self, scope: usize) -> &[Rule] {
+        &self.scopes[scope]
+    }
+
+    /// Add npm `overrides` from a package.json. `root_deps` are used to resolve `$name` references
+    pub(crate) fn add_npm(
+        &mut self,
+        overrides: &BTreeMap<String, OverrideValue>,
+        root_deps: &HashMap<String, String>,
+    ) -> Result<(), CustomErrors> {
+        self.add_npm_scope(Self::ROOT, overrides, root_deps)
+    }
+
+    fn add_npm_scope(
+        &mut self,
+        scope: usize,
+        overrides: &BTreeMap<String, OverrideValue>,
+        root_deps: &HashMap<String, String>,
+    ) -> Result<(), CustomErrors> {
+        for (key, value) in overrides {
+            if key == "." {
+                continue;
+            }
+
+            let (name, range) = split_package_string(key)?;
+            let selector = if range == "latest" {
+                None
+            } else {
+                Some(parse(&range)?)
+            };
+
+            let (spec, nested) = match value {
+                OverrideValue::Spec(spec) => (Some(spec), None),
+                OverrideValue::Nested(nested) => (
+                    match nested.get(".") {
+                        Some(OverrideValue::Spec(spec)) => Some(spec),
+                        _ => None,
+                    },
+                    Some(nested),
+                ),
+            };
+            let spec = match spec {
+                Some(spec) => Some(deref_spec(spec, root_deps)?),
+                None => None,
+            };
+
+            let child = match nested {
+                Some(nested) => {
+                    self.scopes.push(vec![]);
+                    let child = self.scopes.len() - 1;
+                    self.add_npm_scope(child, nested, root_deps)?;
+                    Some(child)
+                }
+                None => None,
+            };
+
+            self.scopes[scope].push(Rule {
+                name,
+                selector,
+                spec,
+                scope: child,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Add Yarn `resolutions`. `a/b` and `a/**/b` both apply within the dependencies of `a`,
+    /// `b` and `**/b` apply everywhere
+    pub(crate) fn add_yarn(
+        &mut self,
+        resolutions: &BTreeMap<String, String>,
+    ) -> Result<(), CustomErrors> {
+        for (key, spec) in resolutions {
+            let mut names = vec![];
+            let mut segments = key.split('/');
+            while let Some(segment) = segments.next() {
+                match segment {
+                    "**" | "" => continue,
+                    s if s.starts_with('@') => names.push(format!(
+                        "{s}/{}",
+                        segments
+                            .next()
+                            .ok_or(CustomErrors::PackageJsonParse(format!(
+                                "invalid resolution {key}"
+                            )))?
+                    )),
+                    s => names.push(s.to_string()),
+                }
+            }
+
+            let Some(target) = names.pop() else {
+                return Err(CustomErrors::PackageJsonParse(format!(
+                    "invalid resolution {key}"
+                )));
+            };
+
+            let mut scope = Self::ROOT;
+            for parent in names {
+                scope = self.child_scope(scope, parent);
+            }
+
+            self.scopes[scope].push(Rule {
+                name: target,
+                selector: None,
+                spec: Some(spec.clone()),
+                scope: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Scope nested under an unconditional rule for `name`, created when missing
+    fn child_scope(&mut self, scope: usize, name: String) -> usize {
+        let existing = self.scopes[scope]
+            .iter()
+            .position(|r| r.name == name && r.selector.is_none() && r.spec.is_none());
+
+        let idx = match existing {
+            Some(idx) => idx,
+            None => {
+                self.scopes[scope].push(Rule {
+                    name,
+                    selector: None,
+                    spec: None,
+                    scope: None,
+                });
+                self.scopes[scope].len() - 1
+            }
+        };
+
+        match self.scopes[scope][idx].scope {
+            Some(child) => child,
+            None => {
+                self.scopes.push(vec![]);
+                let child = self.scopes.len() - 1;
+                self.scopes[scope][idx].scope = Some(child);
+                child
+            }
+        }
+    }
+}
+
+/// Resolve `$name` references to the range declared by the root package
+fn deref_spec(spec: &str, root_deps: &HashMap<String, String>) -> Result<String, CustomErrors> {
+    match spec.strip_prefix('$') {
+        Some(name) => root_deps
+            .get(name)
+            .cloned()
+            .ok_or(CustomErrors::PackageJsonParse(format!(
+                "override reference ${name} isn't a direct dependency"
+            ))),
+        None => Ok(spec.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn npm(json: &str, root_deps: &[(&str, &str)]) -> Result<Overrides, CustomErrors> {
+        let mut overrides = Overrides::default();
+        let root_deps = root_deps
+            .iter()
+            .map(|(n, r)| (n.to_string(), r.to_string()))
+            .collect();
+        overrides.add_npm(&serde_json::from_str(json).unwrap(), &root_deps)?;
+
+        Ok(overrides)
+    }
+
+    fn yarn(resolutions: &[(&str, &str)]) -> Overrides {
+        let mut overrides = Overrides::default();
+        let resolutions = resolutions
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        overrides.add_yarn(&resolutions).unwrap();
+
+        overrides
+    }
+
+    #[test]
+    fn npm_global_and_selected_rules() {
+        let overrides = npm(r#"{"foo": "1.0.0", "bar@^2": "2.1.0"}"#, &[]).unwrap();
+        let rules = overrides.rules(Overrides::ROOT);
+
+        assert_eq!(rules.len(), 2);
+        let bar = rules.iter().find(|r| r.name == "bar").unwrap();
+        assert_eq!(bar.spec.as_deref(), Some("2.1.0"));
+        assert!(bar.selects("bar", Some("2.3.0")));
+        assert!(!bar.selects("bar", Some("1.0.0")));
+        assert!(!bar.selects("bar", None));
+
+        let foo = rules.iter().find(|r| r.name == "foo").unwrap();
+        assert!(foo.selects("foo", None));
+        assert!(!foo.selects("bar", None));
+    }
+
+    #[test]
+    fn npm_nested_rules() {
+        let overrides = npm(r#"{"foo": {".": "1.0.0", "bar": "2.0.0"}}"#, &[]).unwrap();
+        let foo = &overrides.rules(Overrides::ROOT)[0];
+
+        assert_eq!(foo.spec.as_deref(), Some("1.0.0"));
+        let nested = overrides.rules(foo.scope.unwrap());
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].name, "bar");
+        assert_eq!(nested[0].spec.as_deref(), Some("2.0.0"));
+    }
+
+    #[test]
+    fn npm_references() {
+        let overrides = npm(r#"{"foo": "$foo"}"#, &[("foo", "^3.0.0")]).unwrap();
+        assert_eq!(
+            overrides.rules(Overrides::ROOT)[0].spec.as_deref(),
+            Some("^3.0.0")
+        );
+
+        assert!(npm(r#"{"foo": "$foo"}"#, &[]).is_err());
+    }
+
+    #[test]
+    fn yarn_resolutions() {
+        let overrides = yarn(&[
+            ("left-pad", "1.3.0"),
+            ("**/lodash", "4.17.21"),
+            ("a/b", "2.0.0"),
+            ("a/**/@scope/c", "3.0.0"),
+        ]);
+        let root = overrides.rules(Overrides::ROOT);
+
+        let global = root
+            .iter()
+            .filter(|r| r.spec.is_some())
+            .map(|r| (r.name.as_str(), r.spec.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        // resolutions are applied in key order
+        assert_eq!(global, [("lodash", "4.17.21"), ("left-pad", "1.3.0")]);
+
+        // both rules under "a" share its scope
+        let a = root.iter().filter(|r| r.name == "a").collect::<Vec<_>>();
+        assert_eq!(a.len(), 1);
+        let nested = overrides
+            .rules(a[0].scope.unwrap())
+            .iter()
+            .map(|r| (r.name.as_str(), r.spec.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(nested, [("@scope/c", "3.0.0"), ("b", "2.0.0")]);
+    }
+
+    #[test]
+    fn invalid_yarn_resolutions() {
+        let mut overrides = Overrides::default();
+        for key in ["**", "a/@scope"] {
+            let resolutions = BTreeMap::from([(key.to_string(), "1.0.0".to_string())]);
+            assert!(overrides.add_yarn(&resolutions).is_err(), "{key}");
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{fmt, str::FromStr};
+
+use crate::{errors::CustomErrors, serde::Version};
+
+/// Platform packages are installed on, as `os-cpu[-libc]` (linux-x64-glibc, win32-x64)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Platform {
+    os: String,
+    cpu: String,
+    libc: Option<String>,
+}
+
+impl FromStr for Platform {
+    type Err = CustomErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split('-').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [os, cpu] if !os.is_empty() && !cpu.is_empty() => Ok(Self {
+                os: os.to_string(),
+                cpu: cpu.to_string(),
+                libc: None,
+            }),
+            [os, cpu, libc] if !os.is_empty() && !cpu.is_empty() && !libc.is_empty() => Ok(Self {
+                os: os.to_string(),
+                cpu: cpu.to_string(),
+                libc: Some(libc.to_string()),
+            }),
+            _ => Err(CustomErrors::Global(format!(
+                "invalid target \"{s}\", expected os-cpu[-libc] (linux-x64-glibc)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.os, self.cpu)?;
+        if let Some(libc) = &self.libc {
+            write!(f, "-{libc}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Platform {
+    /// Whether a package version can be installed on the platform, according to its
+    /// `os`, `cpu` and `libc` fields. An unknown libc matches any restriction
+    pub(crate) fn supports(&self, version: &Version) -> bool {
+        allowed(&version.os, &self.os)
+            && allowed(&version.cpu, &self.cpu)
+            && self
+                .libc
+                .as_ref()
+                .is_none_or(|libc| allowed(&version.libc, libc))
+    }
+}
+
+/// Check a value against an npm platform list, where "!" prefixed entries are excluded
+fn allowed(list: &[String], value: &str) -> bool {
+    if list.iter().any(|v| v.strip_prefix('!') == Some(value)) {
+        return false;
+    }
+
+    let mut allowed = list.iter().filter(|v| !v.starts_with('!')).peekable();
+    allowed.peek().is_none() || allowed.any(|v| v == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(os: &[&str], cpu: &[&str], libc: &[&str]) -> Version {
+        let list = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        Version {
+            os: list(os),
+            cpu: list(cpu),
+            libc: list(libc),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_targets() {
+        let target = "linux-x64-musl".parse::<Platform>().unwrap();
+        assert_eq!(target.to_string(), "linux-x64-musl");
+        assert_eq!("win32-x64".parse::<Platform>().unwrap().libc, None);
+
+        for invalid in ["linux", "linux--glibc", "a-b-c-d", ""] {
+            assert!(invalid.parse::<Platform>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn supports_matching_platforms() {
+        let linux: Platform = "linux-x64-glibc".parse().unwrap();
+
+        assert!(linux.supports(&version(&[], &[], &[])));
+        assert!(linux.supports(&version(&["linux", "darwin"], &["x64"], &["glibc"])));
+        assert!(!linux.supports(&version(&["darwin"], &[], &[])));
+        assert!(!linux.supports(&version(&[], &["arm64"], &[])));
+        assert!(!linux.supports(&version(&[], &[], &["musl"])));
+    }
+
+    #[test]
+    fn negated_entries() {
+        let linux: Platform = "linux-x64".parse().unwrap();
+
+        assert!(linux.supports(&version(&["!win32"], &[], &[])));
+        assert!(!linux.supports(&version(&["!linux"], &[], &[])));
+        // an unknown libc matches any restriction
+        assert!(linux.supports(&version(&[], &[], &["musl"])));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use super::Node;
+
+/// Simulated node_modules tree, as laid out by npm v7+
+#[derive(Debug, Default)]
+pub(super) struct Tree {
+    nodes: Vec<TreeNode>,
+}
+
+#[derive(Debug, Default)]
+struct TreeNode {
+    /// `None` for project roots
+    package: Option<Node>,
+    parent: Option<usize>,
+    /// Packages placed in this node's node_modules
+    children: BTreeMap<String, usize>,
+    /// Dependencies of this node, and the node providing them
+    deps: BTreeMap<String, usize>,
+}
+
+impl Tree {
+    /// Add a new project root, holding no package
+    pub(super) fn add_root(&mut self) -> usize {
+        self.nodes.push(TreeNode::default());
+        self.nodes.len() - 1
+    }
+
+    pub(super) fn package(&self, idx: usize) -> Option<&Node> {
+        self.nodes[idx].package.as_ref()
+    }
+
+    pub(super) fn child(&self, idx: usize, name: &str) -> Option<usize> {
+        self.nodes[idx].children.get(name).copied()
+    }
+
+    /// Find the node providing `name` to `from`, following node_modules lookup rules
+    pub(super) fn lookup(&self, from: usize, name: &str) -> Option<usize> {
+        let mut current = Some(from);
+        while let Some(idx) = current {
+            if let Some(child) = self.child(idx, name) {
+                return Some(child);
+            }
+            current = self.nodes[idx].parent;
+        }
+
+        None
+    }
+
+    /// Find the shallowest node where `name` can be placed so `from` resolves it,
+    /// without shadowing a version already used deeper in the tree
+    pub(super) fn placement(&self, from: usize, name: &str) -> usize {
+        let mut target = from;
+        let mut current = Some(from);
+        while let Some(idx) = current {
+            if self.nodes[idx].children.contains_key(name) {
+                break;
+            }
+            if !self.shadows(idx, name) {
+                target = idx;
+            }
+            current = self.nodes[idx].parent;
+        }
+
+        target
+    }
+
+    /// Place a package in `target`'s node_modules
+    pub(super) fn place(&mut self, target: usize, package: Node) -> usize {
+        let idx = self.nodes.len();
+        self.nodes[target].children.insert(package.0.clone(), idx);
+        self.nodes.push(TreeNode {
+            package: Some(package),
+            parent: Some(target),
+            ..Default::default()
+        });
+
+        idx
+    }
+
+    /// Record that `from` resolves its dependency `name` to `to`
+    pub(super) fn link(&mut self, from: usize, name: &str, to: usize) {
+        self.nodes[from].deps.insert(name.to_string(), to);
+    }
+
+    /// Versions of `name` placed anywhere in the tree
+    pub(super) fn versions<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.nodes
+            .iter()
+            .filter_map(|n| n.package.as_ref())
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn is_descendant(&self, idx: usize, ancestor: usize) -> bool {
+        let mut current = Some(idx);
+        while let Some(i) = current {
+            if i == ancestor {
+                return true;
+            }
+            current = self.nodes[i].parent;
+        }
+
+        false
+    }
+
+    /// Whether placing `name` in `target` would change what an already resolved node below it uses
+    fn shadows(&self, target: usize, name: &str) -> bool {
+        self.nodes.iter().enumerate().any(|(idx, node)| {
+            node.deps.get(name).is_some_and(|&dep| {
+                self.is_descendant(idx, target) && !self.is_descendant(dep, target)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, version: &str) -> Node {
+        (name.to_string(), version.to_string())
+    }
+
+    #[test]
+    fn hoists_to_root() {
+        let mut tree = Tree::default();
+        let root = tree.add_root();
+        let a = tree.place(root, node("a", "1.0.0"));
+
+        assert_eq!(tree.placement(a, "b"), root);
+    }
+
+    #[test]
+    fn conflicting_versions_stay_nested() {
+        let mut tree = Tree::default();
+        let root = tree.add_root();
+        let b = tree.place(root, node("b", "1.0.0"));
+        let a = tree.place(root, node("a", "1.0.0"));
+        tree.link(root, "b", b);
+
+        // a needs another b, which can't replace the root's one
+        assert_eq!(tree.placement(a, "b"), a);
+        let nested = tree.place(a, node("b", "2.0.0"));
+        tree.link(a, "b", nested);
+
+        assert_eq!(tree.lookup(a, "b"), Some(nested));
+        assert_eq!(tree.lookup(root, "b"), Some(b));
+        assert_eq!(tree.versions("b").collect::<Vec<_>>(), ["1.0.0", "2.0.0"]);
+    }
+
+    #[test]
+    fn placement_does_not_shadow_resolved_dependencies() {
+        let mut tree = Tree::default();
+        let root = tree.add_root();
+        let c = tree.place(root, node("c", "1.0.0"));
+        let p = tree.place(root, node("p", "1.0.0"));
+        let q = tree.place(p, node("q", "1.0.0"));
+        tree.link(q, "c", c);
+        let m = tree.place(p, node("m", "1.0.0"));
+        let k = tree.place(m, node("k", "1.0.0"));
+
+        // placing another c in p would change the c used by q
+        assert_eq!(tree.placement(k, "c"), m);
+    }
+}
@@ -1,13 +1,18 @@
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PackageJson {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
     #[serde(default = "HashMap::new")]
     pub dependencies: HashMap<String, String>,
     #[serde(default = "HashMap::new")]
@@ -16,6 +21,40 @@ pub struct PackageJson {
     pub peer_dependencies: HashMap<String, String>,
     #[serde(default = "HashMap::new")]
     pub optional_dependencies: HashMap<String, String>,
+    /// npm overrides
+    #[serde(default = "BTreeMap::new")]
+    pub overrides: BTreeMap<String, OverrideValue>,
+    /// Yarn resolutions
+    #[serde(default = "BTreeMap::new")]
+    pub resolutions: BTreeMap<String, String>,
+    #[serde(default)]
+    pub workspaces: Option<Workspaces>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Workspaces {
+    List(Vec<String>),
+    Object {
+        #[serde(default)]
+        packages: Vec<String>,
+    },
+}
+
+impl Workspaces {
+    pub fn patterns(&self) -> &[String] {
+        match self {
+            Workspaces::List(patterns) => patterns,
+            Workspaces::Object { packages } => packages,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OverrideValue {
+    Spec(String),
+    Nested(BTreeMap<String, OverrideValue>),
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -23,6 +62,9 @@ pub struct PackageRsp {
     #[serde(rename = "dist-tags")]
     pub dist_tags: HashMap<String, String>,
     pub versions: BTreeMap<String, Version>,
+    /// Publication dates of each version. Only sent in full packuments
+    #[serde(default = "HashMap::new")]
+    pub time: HashMap<String, String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -38,10 +80,135 @@ pub struct Version {
     pub peer_dependencies: HashMap<String, String>,
     #[serde(rename = "optionalDependencies", default = "HashMap::new")]
     pub optional_dependencies: HashMap<String, String>,
+    #[serde(rename = "peerDependenciesMeta", default = "HashMap::new")]
+    pub peer_dependencies_meta: HashMap<String, PeerDependencyMeta>,
+    #[serde(rename = "bundleDependencies", default)]
+    pub bundle_dependencies: Option<BundleDependencies>,
+    #[serde(rename = "bundledDependencies", default)]
+    pub bundled_dependencies: Option<BundleDependencies>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub os: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub cpu: Vec<String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub libc: Vec<String>,
+    #[serde(default, deserialize_with = "string_map")]
+    pub engines: HashMap<String, String>,
+    /// Deprecation message
+    #[serde(default, deserialize_with = "deprecation")]
+    pub deprecated: Option<String>,
+}
+
+impl Version {
+    /// Whether a peer dependency is marked optional in peerDependenciesMeta
+    pub fn is_optional_peer(&self, name: &str) -> bool {
+        self.peer_dependencies_meta
+            .get(name)
+            .is_some_and(|meta| meta.optional)
+    }
+
+    /// Names of dependencies shipped inside the package tarball
+    pub fn bundled(&self) -> HashSet<&str> {
+        [&self.bundle_dependencies, &self.bundled_dependencies]
+            .into_iter()
+            .flatten()
+            .flat_map(|bundled| match bundled {
+                BundleDependencies::All(true) => self
+                    .dependencies
+                    .keys()
+                    .chain(self.optional_dependencies.keys())
+                    .map(String::as_str)
+                    .collect::<Vec<_>>(),
+                BundleDependencies::All(false) => vec![],
+                BundleDependencies::List(names) => names.iter().map(String::as_str).collect(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerDependencyMeta {
+    #[serde(default)]
+    pub optional: bool,
+}
+
+/// `bundleDependencies` is either a list of names or `true` to bundle every dependency
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BundleDependencies {
+    All(bool),
+    List(Vec<String>),
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Dist {
     pub tarball: String,
     pub shasum: String,
+    /// Subresource Integrity string (sha512-...), missing from old packages
+    #[serde(default)]
+    pub integrity: Option<String>,
+}
+
+/// Deserialize a field published either as a single string or a list of strings
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => vec![s],
+        serde_json::Value::Array(list) => list
+            .into_iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        _ => vec![],
+    })
+}
+
+/// Deserialize a map of strings, ignoring malformed values (some old packages publish `engines` as a list)
+fn string_map<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Object(map) => map
+            .into_iter()
+            .filter_map(|(k, v)| v.as_str().map(|v| (k, v.to_string())))
+            .collect(),
+        _ => HashMap::new(),
+    })
+}
+
+/// Deserialize a deprecation message. Some packages publish booleans instead of a message
+fn deprecation<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) if !s.is_empty() => Some(s),
+        serde_json::Value::Bool(true) => Some(String::new()),
+        _ => None,
+    })
+}
+
+/// Bundle index, listing where each downloaded package version came from
+pub type Index = BTreeMap<String, BTreeMap<String, IndexEntry>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Registry which served the package manifest
+    pub registry: Option<String>,
+    /// URL the tarball was downloaded from
+    pub tarball: String,
+    /// Tarball file name within the bundle
+    pub file: String,
+}
+
+/// Registry response stored in the cache, with the validators used to revalidate it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedResponse {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
 }
@@ -0,0 +1,179 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use sha2::Sha512;
+
+use crate::{cache::cache_dir, errors::CustomErrors, serde::Dist};
+
+/// How tarballs of the store are placed into output directories
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LinkMethod {
+    /// Reflink when the filesystem supports it, otherwise copy
+    Auto,
+    /// Copy-on-write clone of the stored file
+    Reflink,
+    /// Hard link to the stored file. Modifying the output modifies the store
+    Hardlink,
+    Copy,
+}
+
+/// Expected digest of a tarball, from its Subresource Integrity string (sha512-...),
+/// or its legacy SHA-1 shasum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Integrity {
+    algorithm: Algorithm,
+    digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Sha1,
+    Sha512,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha512 => "sha512",
+        }
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.algorithm.name(),
+            STANDARD.encode(&self.digest)
+        )
+    }
+}
+
+impl Integrity {
+    /// Strongest digest known for a tarball
+    pub(super) fn of(dist: &Dist) -> Result<Self, CustomErrors> {
+        let sha512 = dist.integrity.as_deref().and_then(|integrity| {
+            integrity
+                .split_whitespace()
+                .filter_map(|hash| hash.strip_prefix("sha512-"))
+                .find_map(|digest| STANDARD.decode(digest.split('?').next()?).ok())
+        });
+        if let Some(digest) = sha512 {
+            return Ok(Self {
+                algorithm: Algorithm::Sha512,
+                digest,
+            });
+        }
+
+        let digest = base16ct::mixed::decode_vec(&dist.shasum).map_err(|e| {
+            CustomErrors::Global(format!("invalid shasum \"{}\": {e}", dist.shasum))
+        })?;
+        Ok(Self {
+            algorithm: Algorithm::Sha1,
+            digest,
+        })
+    }
+
+    /// Whether content matches the digest
+    pub(super) fn matches(&self, content: &[u8]) -> bool {
+        let digest = match self.algorithm {
+            Algorithm::Sha1 => Sha1::digest(content).to_vec(),
+            Algorithm::Sha512 => Sha512::digest(content).to_vec(),
+        };
+
+        digest == self.digest
+    }
+
+    /// Whether a file's content matches the digest. Missing files don't match
+    pub(super) fn matches_file(&self, path: &Path) -> bool {
+        fs::read(path).is_ok_and(|content| self.matches(&content))
+    }
+}
+
+/// Content-addressable tarball store shared by every output directory, keyed by integrity
+#[derive(Debug, Clone)]
+pub(super) struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Open the store of a cache directory, or of the default one of the user
+    pub(super) fn new(cache: Option<&Path>) -> Self {
+        Self {
+            dir: cache_dir(cache).join("tarballs"),
+        }
+    }
+
+    /// Path of a stored tarball, if any. Tarballs altered through hard links are dropped
+    pub(super) fn get(&self, integrity: &Integrity) -> Option<PathBuf> {
+        let path = self.path(integrity);
+        if integrity.matches_file(&path) {
+            return Some(path);
+        }
+
+        let _ = fs::remove_file(&path);
+        None
+    }
+
+    /// Store a tarball after checking its integrity
+    pub(super) fn insert(
+        &self,
+        integrity: &Integrity,
+        content: &[u8],
+    ) -> Result<PathBuf, CustomErrors> {
+        if !integrity.matches(content) {
+            return Err(CustomErrors::Global(format!(
+                "tarball doesn't match its integrity {integrity}"
+            )));
+        }
+
+        let path = self.path(integrity);
+        let dir = path
+            .parent()
+            .expect("stored tarballs are in a subdirectory");
+        fs::create_dir_all(dir).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        fs::rename(&tmp, &path).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+
+        Ok(path)
+    }
+
+    fn path(&self, integrity: &Integrity) -> PathBuf {
+        let hex = base16ct::lower::encode_string(&integrity.digest);
+
+        self.dir
+            .join(integrity.algorithm.name())
+            .join(&hex[..2])
+            .join(&hex[2..])
+    }
+}
+
+/// Place a stored tarball at `dest`, replacing any existing file
+pub(super) fn link(stored: &Path, dest: &Path, method: LinkMethod) -> Result<(), CustomErrors> {
+    if dest.exists() {
+        fs::remove_file(dest).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+    }
+
+    let linked = match method {
+        LinkMethod::Auto => reflink_copy::reflink_or_copy(stored, dest).map(|_| ()),
+        LinkMethod::Reflink => reflink_copy::reflink(stored, dest),
+        LinkMethod::Hardlink => fs::hard_link(stored, dest),
+        LinkMethod::Copy => fs::copy(stored, dest).map(|_| ()),
+    };
+
+    linked.map_err(|e| {
+        CustomErrors::Fs(format!(
+            "failed to link {} to {}: {e}",
+            stored.display(),
+            dest.display()
+        ))
+    })
+}
@@ -1,60 +1,389 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use flate2::read::GzDecoder;
 
 use semver::VersionReq;
 
 use crate::{errors::CustomErrors, serde::Version, version::parse};
 
+/// Find the highest version matching `pre`, or the `lts` tagged version when given.
+/// With a `node` version, versions whose `engines.node` excludes it are skipped. A tagged version
+/// missing from `src` or excluded by its engines falls back to the highest compatible version below it.
+/// Deprecated versions are only picked when no other version qualifies.
+/// With `lowest`, the lowest version matching `pre` is picked instead
 pub(crate) fn find_version(
-    src: BTreeMap<String, Version>,
+    src: &BTreeMap<String, Version>,
     pre: Option<Vec<VersionReq>>,
     lts: Option<&str>,
+    node: Option<&semver::Version>,
+    lowest: bool,
 ) -> Result<Option<Version>, CustomErrors> {
-    if let Some(lts) = lts {
-        if let Some(v) = src.get(lts) {
-            return Ok(Some(v.to_owned()));
-        } else {
-            return Ok(None);
-        }
-    }
+    let compatible = |v: &Version| node.is_none_or(|node| supports_node(v, node));
 
-    let mut found = None;
-    let pre = pre.expect("pre can't be optional if lts is None");
+    let candidates = if let Some(lts) = lts {
+        let parsed_lts =
+            semver::Version::parse(lts).map_err(|e| CustomErrors::VersionParse(e.to_string()))?;
 
-    for (tag, v) in src.iter().rev() {
-        let parsed_v =
-            semver::Version::parse(tag).map_err(|e| CustomErrors::VersionParse(e.to_string()))?;
-        let matched = pre.iter().find(|req| req.matches(&parsed_v)).is_some();
-        if !matched && found.is_some() {
-            break;
-        }
+        src.get(lts)
+            .filter(|v| compatible(v))
+            .into_iter()
+            .chain(
+                sorted_versions(src)?
+                    .into_iter()
+                    .filter(|(parsed_v, v)| {
+                        parsed_v < &parsed_lts && parsed_v.pre.is_empty() && compatible(v)
+                    })
+                    .map(|(_, v)| v),
+            )
+            .collect::<Vec<_>>()
+    } else {
+        let pre = pre.expect("pre can't be optional if lts is None");
 
-        if matched {
-            found = Some(v.clone());
+        let mut versions = sorted_versions(src)?;
+        if lowest {
+            versions.reverse();
         }
+
+        versions
+            .into_iter()
+            .filter(|(parsed_v, v)| pre.iter().any(|req| req.matches(parsed_v)) && compatible(v))
+            .map(|(_, v)| v)
+            .collect::<Vec<_>>()
+    };
+
+    Ok(candidates
+        .iter()
+        .find(|v| v.deprecated.is_none())
+        .or(candidates.first())
+        .map(|v| (*v).clone()))
+}
+
+/// List up to `count` versions closest to a requirement: the highest ones below its lower bound
+/// and the lowest ones above it. Without requirement, the highest versions are listed
+pub(crate) fn nearest_versions(
+    src: &BTreeMap<String, Version>,
+    req: Option<&[VersionReq]>,
+    count: usize,
+) -> Vec<String> {
+    let mut versions = src
+        .keys()
+        .filter_map(|v| semver::Version::parse(v).ok())
+        .collect::<Vec<_>>();
+    versions.sort();
+
+    let pivot = req
+        .and_then(|req| req.first())
+        .and_then(|req| req.comparators.first())
+        .map(|c| semver::Version::new(c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0)));
+    let split = match &pivot {
+        Some(pivot) => versions.partition_point(|v| v < pivot),
+        None => versions.len(),
+    };
+
+    let below = &versions[..split];
+    let above = &versions[split..];
+    let above_count = above.len().min(count / 2);
+    let below_count = below.len().min(count - above_count);
+
+    below[below.len() - below_count..]
+        .iter()
+        .chain(&above[..above.len().min(count - below_count)])
+        .map(|v| v.to_string())
+        .collect()
+}
+
+/// Parse versions of a package, sorted from highest to lowest
+fn sorted_versions(
+    src: &BTreeMap<String, Version>,
+) -> Result<Vec<(semver::Version, &Version)>, CustomErrors> {
+    let mut versions = src
+        .iter()
+        .map(|(tag, v)| {
+            semver::Version::parse(tag)
+                .map(|parsed| (parsed, v))
+                .map_err(|e| CustomErrors::VersionParse(e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    Ok(versions)
+}
+
+/// Whether a package version's `engines.node` range accepts a Node.js version.
+/// Missing or unparsable ranges accept any version
+pub(crate) fn supports_node(version: &Version, node: &semver::Version) -> bool {
+    let Some(range) = version.engines.get("node") else {
+        return true;
+    };
+
+    match parse(range) {
+        Ok(reqs) => reqs.iter().any(|r| r.matches(node)),
+        Err(_) => true,
+    }
+}
+
+/// Parse a date given either as RFC 3339 (2023-10-01T12:00:00Z) or as a day (2023-10-01, midnight UTC)
+pub(crate) fn parse_date(date: &str) -> Result<DateTime<Utc>, CustomErrors> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date.with_timezone(&Utc));
     }
 
-    Ok(found)
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map(|d| d.and_time(NaiveTime::MIN).and_utc())
+        .map_err(|e| CustomErrors::Global(format!("invalid date \"{date}\": {e}")))
 }
 
-/// Split a package string into a tuple of package name and version
-pub(crate) fn split_package_string(
-    package: String,
-) -> Result<(String, Option<Vec<VersionReq>>), CustomErrors> {
-    let mut splitted = package.split('@').collect::<Vec<&str>>();
-    if splitted.len() > 3 {
+/// Parse a Node.js version the way `node --version` prints it (v16.20.0), or partially (16, 16.20),
+/// missing minor and patch numbers being 0
+pub(crate) fn parse_node_version(version: &str) -> Result<semver::Version, CustomErrors> {
+    let trimmed = version.trim();
+    let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+    let core = trimmed.split(['-', '+']).next().unwrap_or_default();
+    let missing = 2usize.saturating_sub(core.matches('.').count());
+    let full = format!("{core}{}{}", ".0".repeat(missing), &trimmed[core.len()..]);
+
+    semver::Version::parse(&full)
+        .map_err(|e| CustomErrors::Global(format!("invalid Node.js version \"{version}\": {e}")))
+}
+
+/// Split a package string into a tuple of package name and version range.
+/// Range defaults to "latest" when omitted
+pub(crate) fn split_package_string(package: &str) -> Result<(String, String), CustomErrors> {
+    let (scope, unscoped) = match package.strip_prefix('@') {
+        Some(unscoped) => ("@", unscoped),
+        None => ("", package),
+    };
+
+    let splitted = unscoped.split('@').collect::<Vec<&str>>();
+    if splitted.len() > 2 {
         return Err(CustomErrors::PackageSplit(format!(
             "package name can only contains a maximum of 2 '@'. Found {}",
-            splitted.len() - 1
+            splitted.len() - 1 + scope.len()
+        )));
+    }
+    if splitted[0].is_empty() {
+        return Err(CustomErrors::PackageSplit(format!(
+            "missing package name in \"{package}\""
         )));
-    } else if splitted.len() == 3 {
-        splitted.remove(0);
     }
 
-    let version = if splitted[1] == "latest" {
-        None
-    } else {
-        Some(parse(splitted[1])?)
-    };
+    Ok((
+        format!("{scope}{}", splitted[0]),
+        splitted.get(1).unwrap_or(&"latest").to_string(),
+    ))
+}
+
+/// List bundled dependencies missing from a package tarball
+pub(crate) fn missing_bundled<'a>(
+    tarball: &Path,
+    bundled: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<&'a str>, CustomErrors> {
+    let f = File::open(tarball).map_err(|e| CustomErrors::Fs(e.to_string()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(f));
+
+    let mut entries = vec![];
+    for entry in archive
+        .entries()
+        .map_err(|e| CustomErrors::Fs(e.to_string()))?
+    {
+        let entry = entry.map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        let path = entry.path().map_err(|e| CustomErrors::Fs(e.to_string()))?;
+        entries.push(path.to_string_lossy().to_string());
+    }
+
+    // tarballs root directory is usually "package/", but not always
+    let mut missing = bundled
+        .into_iter()
+        .filter(|name| {
+            let manifest = format!("node_modules/{name}/package.json");
+            !entries
+                .iter()
+                .any(|e| e.split_once('/').is_some_and(|(_, rest)| rest == manifest))
+        })
+        .collect::<Vec<_>>();
+    missing.sort();
+
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(list: &[&str]) -> BTreeMap<String, Version> {
+        list.iter()
+            .map(|v| (v.to_string(), Version::default()))
+            .collect()
+    }
+
+    fn picked(
+        src: &BTreeMap<String, Version>,
+        range: Option<&str>,
+        latest: Option<&str>,
+        node: Option<&str>,
+        lowest: bool,
+    ) -> Option<String> {
+        let node = node.map(|n| semver::Version::parse(n).unwrap());
+        find_version(
+            src,
+            range.map(|r| parse(r).unwrap()),
+            latest,
+            node.as_ref(),
+            lowest,
+        )
+        .unwrap()
+        .map(|v| v.version)
+    }
+
+    /// Versions named after their keys
+    fn named(mut src: BTreeMap<String, Version>) -> BTreeMap<String, Version> {
+        src.iter_mut().for_each(|(k, v)| v.version = k.clone());
+        src
+    }
+
+    #[test]
+    fn deprecated_versions_are_a_last_resort() {
+        let mut src = named(versions(&["1.0.0", "1.1.0", "1.2.0"]));
+        src.get_mut("1.2.0").unwrap().deprecated = Some("use 1.1.0".to_string());
+
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, None, false).as_deref(),
+            Some("1.1.0")
+        );
+        assert_eq!(
+            picked(&src, Some("1.2.0"), None, None, false).as_deref(),
+            Some("1.2.0")
+        );
+    }
+
+    #[test]
+    fn lowest_matching_version() {
+        let src = named(versions(&["0.9.0", "1.0.0", "1.1.0", "1.10.0", "2.0.0"]));
+
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, None, true).as_deref(),
+            Some("1.0.0")
+        );
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, None, false).as_deref(),
+            Some("1.10.0")
+        );
+        assert_eq!(
+            picked(&src, Some(">1.0.0 <2.0.0"), None, None, true).as_deref(),
+            Some("1.1.0")
+        );
+    }
+
+    #[test]
+    fn latest_falls_back_below_tag() {
+        let mut src = named(versions(&[
+            "1.0.0",
+            "1.1.0",
+            "2.0.0-beta.1",
+            "2.0.0",
+            "3.0.0",
+        ]));
+        src.get_mut("2.0.0")
+            .unwrap()
+            .engines
+            .insert("node".to_string(), ">=18".to_string());
+
+        // tag excluded by engines: highest compatible stable version below it
+        assert_eq!(
+            picked(&src, None, Some("2.0.0"), Some("16.20.0"), false).as_deref(),
+            Some("1.1.0")
+        );
+        assert_eq!(
+            picked(&src, None, Some("2.0.0"), Some("18.0.0"), false).as_deref(),
+            Some("2.0.0")
+        );
 
-    return Ok((splitted[0].to_string(), version));
+        // tag filtered out of the versions
+        src.remove("2.0.0");
+        assert_eq!(
+            picked(&src, None, Some("2.0.0"), None, false).as_deref(),
+            Some("1.1.0")
+        );
+    }
+
+    #[test]
+    fn engines_node_excludes_versions() {
+        let mut src = named(versions(&["1.0.0", "1.1.0", "1.2.0"]));
+        for (v, range) in [("1.1.0", ">=14"), ("1.2.0", ">=18 || ^16.20.0")] {
+            src.get_mut(v)
+                .unwrap()
+                .engines
+                .insert("node".to_string(), range.to_string());
+        }
+
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, Some("16.0.0"), false).as_deref(),
+            Some("1.1.0")
+        );
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, Some("16.20.2"), false).as_deref(),
+            Some("1.2.0")
+        );
+        assert_eq!(
+            picked(&src, Some("^1.0.0"), None, Some("12.0.0"), false).as_deref(),
+            Some("1.0.0")
+        );
+        assert_eq!(
+            picked(&src, Some("^1.1.0"), None, Some("12.0.0"), false),
+            None
+        );
+    }
+
+    #[test]
+    fn nearest_around_lower_bound() {
+        let src = versions(&[
+            "1.0.0", "1.5.0", "2.0.0", "2.1.0", "3.0.0", "4.0.0", "5.0.0",
+        ]);
+        let req = parse("^2.5.0").unwrap();
+
+        assert_eq!(
+            nearest_versions(&src, Some(&req), 4),
+            ["2.0.0", "2.1.0", "3.0.0", "4.0.0"]
+        );
+    }
+
+    #[test]
+    fn nearest_fills_from_one_side() {
+        let src = versions(&["1.0.0", "1.1.0", "1.2.0", "1.3.0"]);
+
+        // nothing above the bound
+        let req = parse("^9.0.0").unwrap();
+        assert_eq!(
+            nearest_versions(&src, Some(&req), 3),
+            ["1.1.0", "1.2.0", "1.3.0"]
+        );
+
+        // nothing below the bound
+        let req = parse("<1.0.0").unwrap();
+        assert_eq!(nearest_versions(&src, Some(&req), 2), ["1.0.0", "1.1.0"]);
+    }
+
+    #[test]
+    fn nearest_without_requirement() {
+        let src = versions(&["0.1.0", "1.0.0", "10.0.0", "2.0.0"]);
+
+        assert_eq!(nearest_versions(&src, None, 2), ["2.0.0", "10.0.0"]);
+        assert!(nearest_versions(&BTreeMap::new(), None, 4).is_empty());
+    }
+
+    #[test]
+    fn node_versions() {
+        for (given, parsed) in [
+            ("16", "16.0.0"),
+            ("v16.20", "16.20.0"),
+            ("v16.20.0", "16.20.0"),
+            ("18.0.0-rc.1", "18.0.0-rc.1"),
+            ("20-nightly", "20.0.0-nightly"),
+        ] {
+            assert_eq!(parse_node_version(given).unwrap().to_string(), parsed);
+        }
+        assert!(parse_node_version("lts").is_err());
+        assert!(parse_node_version("16.x").is_err());
+    }
 }
@@ -63,9 +63,24 @@ pub(crate) fn parse(input: &str) -> Result<Vec<VersionReq>, crate::errors::Custo
         req = VersionReq::default();
     }
 
-    if req.comparators.len() > 0 {
+    if !req.comparators.is_empty() {
         reqs.push(req);
     }
 
     Ok(reqs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unions_with_spaced_operators() {
+        let reqs = parse("^1.0.0 || >= 2.1.0 <3").unwrap();
+
+        assert_eq!(
+            reqs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["^1.0.0", ">=2.1.0, <3"]
+        );
+    }
+}
@@ -87,7 +87,7 @@ fn parse_pre(input: &str) -> IResult<&str, Prerelease> {
 
 /// Parse a version string into `semver::VersionReq`
 pub(super) fn parse_comparator(input: &str) -> IResult<&str, Comparator> {
-    let (input, mut op) = context("range-operator", parse_range)(input)?;
+    let (input, mut op) = context("range-operator", parse_range)(input.trim_start())?;
     let (input, (major, minor, patch)) = context("version", parse_version)(input.trim_start())?;
     let (input, pre) = context("pre-release", parse_pre)(input.trim_start())?;
     let minor = if let Some(minor) = minor {
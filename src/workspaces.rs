@@ -0,0 +1,88 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{errors::CustomErrors, serde::PackageJson, version::parse};
+
+/// Workspace packages of a monorepo, indexed by name. Values are their local version
+pub(crate) type Locals = HashMap<String, Option<String>>;
+
+/// Read a package.json file
+pub(crate) fn read_package_json(path: &Path) -> Result<PackageJson, CustomErrors> {
+    serde_json::from_str(&fs::read_to_string(path).map_err(|e| CustomErrors::Fs(e.to_string()))?)
+        .map_err(|e| CustomErrors::PackageJsonParse(format!("{}: {e}", path.display())))
+}
+
+/// Expand the `workspaces` patterns of a root package.json located in `root`.
+/// Patterns prefixed with "!" exclude matching directories
+pub(crate) fn expand(
+    root: &Path,
+    pkg_json: &PackageJson,
+) -> Result<Vec<PackageJson>, CustomErrors> {
+    let Some(workspaces) = &pkg_json.workspaces else {
+        return Ok(vec![]);
+    };
+
+    let mut included = vec![];
+    let mut excluded = HashSet::new();
+    for pattern in workspaces.patterns() {
+        let (pattern, negated) = match pattern.strip_prefix('!') {
+            Some(pattern) => (pattern, true),
+            None => (pattern.as_str(), false),
+        };
+
+        for dir in glob_dirs(root, pattern)? {
+            if negated {
+                excluded.insert(dir);
+            } else if !included.contains(&dir) {
+                included.push(dir);
+            }
+        }
+    }
+
+    included
+        .into_iter()
+        .filter(|dir| !excluded.contains(dir))
+        .map(|dir| read_package_json(&dir.join("package.json")))
+        .collect()
+}
+
+/// Directories matching a workspace pattern and holding a package.json
+fn glob_dirs(root: &Path, pattern: &str) -> Result<Vec<PathBuf>, CustomErrors> {
+    let pattern = root
+        .join(pattern.trim_start_matches("./").trim_end_matches('/'))
+        .join("package.json");
+    let pattern = pattern.to_str().ok_or(CustomErrors::Global(
+        "failed to convert workspace pattern to string".to_string(),
+    ))?;
+
+    let mut dirs = glob::glob(pattern)
+        .map_err(|e| CustomErrors::PackageJsonParse(format!("invalid workspace pattern: {e}")))?
+        .filter_map(Result::ok)
+        .filter(|p| !p.components().any(|c| c.as_os_str() == "node_modules"))
+        .filter_map(|p| p.parent().map(Path::to_path_buf))
+        .collect::<Vec<_>>();
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+/// Whether a dependency refers to a workspace package, either through the `workspace:`
+/// protocol or with a range matching the local version
+pub(crate) fn is_local(locals: &Locals, name: &str, range: &str) -> bool {
+    let Some(version) = locals.get(name) else {
+        return false;
+    };
+
+    if range.starts_with("workspace:") {
+        return true;
+    }
+
+    let (Some(version), Ok(reqs)) = (version, parse(range)) else {
+        return false;
+    };
+
+    semver::Version::parse(version).is_ok_and(|v| reqs.iter().any(|r| r.matches(&v)))
+}